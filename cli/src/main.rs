@@ -23,9 +23,9 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     ..Default::default()
   };
   let mut client = if args.no_process_scan {
-    rsrpc::RPCServer::from_json_str("[]", config).expect("Failed to create RPCServer")
+    rsrpc::RPCServer::from_json_str("[]", config)?
   } else if let Some(file) = args.detectable_file {
-    rsrpc::RPCServer::from_file(file, config).expect("Failed to create RPCServer")
+    rsrpc::RPCServer::from_file(file, config)?
   } else {
     let detectable = ureq::get("https://discord.com/api/v9/applications/detectable")
       .call()?
@@ -33,11 +33,11 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
       .with_config()
       .limit(32 * 1024 * 1024)
       .read_to_string()?;
-    rsrpc::RPCServer::from_json_str(detectable, config).expect("Failed to create RPCServer")
+    rsrpc::RPCServer::from_json_str(detectable, config)?
   };
 
   // Starts the other threads (process detector, client connector, etc)
-  client.start();
+  client.start()?;
 
   let (tx, rx) = std::sync::mpsc::channel();
   ctrlc::set_handler(move || {
@@ -49,10 +49,7 @@ pub fn main() -> Result<(), Box<dyn std::error::Error>> {
   let _ = rx.recv();
 
   println!("Shutting down...");
-  drop(client);
-
-  // giving them a bit so they can clean up (e.g. drop BoundListener)
-  std::thread::sleep(std::time::Duration::from_millis(100));
+  client.stop();
 
   Ok(())
 }
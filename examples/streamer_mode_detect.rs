@@ -39,7 +39,7 @@ pub fn main() {
   });
 
   // Starts the other threads (process detector, client connector, etc)
-  client.start();
+  client.start().expect("Failed to start RPCServer");
 
   // let 'er run forever
   loop {
@@ -0,0 +1,216 @@
+use std::{
+  collections::VecDeque,
+  io::{ErrorKind, Read, Write},
+  path::Path,
+};
+
+use interprocess::local_socket::Stream;
+use serde_json::{json, Value};
+
+use crate::{
+  cmd::{Activity, ActivityCmdArgs},
+  server::{
+    ipc,
+    ipc_utils::{
+      encode, FrameCodec, FrameError, Handshake, PacketType, DEFAULT_MAX_FRAME_SIZE, IPC_SLOTS,
+    },
+  },
+  Error,
+};
+
+/**
+ * A connection to an RPC server over IPC, as a game would make it. Works against both rsRPC and Discord itself.
+ */
+pub struct IpcClient {
+  stream: Stream,
+  codec: FrameCodec,
+  ready: Value,
+  next_nonce: u64,
+  /// Dispatches that arrived while waiting for a command's response
+  pending: VecDeque<Value>,
+}
+
+impl IpcClient {
+  /**
+   * Connect to the first `discord-ipc-N` socket that accepts us, in the default location
+   */
+  pub fn connect(client_id: &str) -> Result<Self, Error> {
+    Self::connect_with(None, "discord-ipc", client_id)
+  }
+
+  /**
+   * Connect to the first `<socket_prefix>-N` socket in `socket_dir` that accepts us, like the server's
+   * `ipc_socket_dir` and `ipc_socket_prefix` options
+   */
+  pub fn connect_with(
+    socket_dir: Option<&Path>,
+    socket_prefix: &str,
+    client_id: &str,
+  ) -> Result<Self, Error> {
+    let socket_base = ipc::get_socket_path(socket_dir, socket_prefix);
+    let mut last_err = None;
+
+    for slot in 0..IPC_SLOTS {
+      match ipc::connect(&format!("{socket_base}-{slot}")) {
+        Ok(stream) => return Self::handshake(stream, client_id),
+        Err(err) => last_err = Some(err),
+      }
+    }
+
+    Err(Error::IpcSocketUnavailable(last_err.unwrap_or_else(|| {
+      std::io::Error::new(ErrorKind::NotFound, "no IPC slot to connect to")
+    })))
+  }
+
+  fn handshake(stream: Stream, client_id: &str) -> Result<Self, Error> {
+    let mut client = Self {
+      stream,
+      codec: FrameCodec::new(DEFAULT_MAX_FRAME_SIZE),
+      ready: Value::Null,
+      next_nonce: 0,
+      pending: VecDeque::new(),
+    };
+
+    let handshake = Handshake {
+      v: 1,
+      client_id: client_id.to_string(),
+    };
+    client.send(
+      PacketType::Handshake,
+      &serde_json::to_string(&handshake).unwrap_or_default(),
+    )?;
+
+    let ready = client.read_message()?;
+
+    if ready["evt"] != "READY" {
+      return Err(Error::InvalidFrame(format!("expected READY, got {ready}")));
+    }
+
+    client.ready = ready;
+
+    Ok(client)
+  }
+
+  /**
+   * The READY dispatch the server answered the handshake with, which includes the user it is logged in as
+   */
+  pub fn ready(&self) -> &Value {
+    &self.ready
+  }
+
+  /**
+   * Set the activity for this process, or clear it with `None`. Returns the server's response.
+   */
+  pub fn set_activity(&mut self, activity: Option<Activity>) -> Result<Value, Error> {
+    let args = ActivityCmdArgs {
+      pid: Some(std::process::id() as u64),
+      activity,
+      code: None,
+    };
+
+    self.command(
+      "SET_ACTIVITY",
+      serde_json::to_value(args).unwrap_or_default(),
+      None,
+    )
+  }
+
+  /**
+   * Subscribe to an event like `ACTIVITY_JOIN`, after which it shows up in `recv()`
+   */
+  pub fn subscribe(&mut self, evt: &str) -> Result<Value, Error> {
+    self.command("SUBSCRIBE", json!({}), Some(evt))
+  }
+
+  pub fn unsubscribe(&mut self, evt: &str) -> Result<Value, Error> {
+    self.command("UNSUBSCRIBE", json!({}), Some(evt))
+  }
+
+  /**
+   * Send any command and wait for its response, which is returned as-is, errors (`"evt": "ERROR"`) included
+   */
+  pub fn command(&mut self, cmd: &str, args: Value, evt: Option<&str>) -> Result<Value, Error> {
+    self.next_nonce += 1;
+    let nonce = self.next_nonce.to_string();
+
+    let message = json!({
+      "cmd": cmd,
+      "args": args,
+      "evt": evt,
+      "nonce": nonce,
+    });
+    self.send(PacketType::Frame, &message.to_string())?;
+
+    loop {
+      let message = self.read_message()?;
+
+      if message["nonce"] == nonce.as_str() {
+        return Ok(message);
+      }
+
+      self.pending.push_back(message);
+    }
+  }
+
+  /**
+   * Wait for the next message that isn't a response to one of our commands, such as a subscribed event
+   */
+  pub fn recv(&mut self) -> Result<Value, Error> {
+    match self.pending.pop_front() {
+      Some(message) => Ok(message),
+      None => self.read_message(),
+    }
+  }
+
+  /**
+   * Tell the server we are going away, and disconnect
+   */
+  pub fn close(mut self) -> Result<(), Error> {
+    self.send(PacketType::Close, "{}")
+  }
+
+  fn send(&mut self, r_type: PacketType, data: &str) -> Result<(), Error> {
+    self.stream.write_all(&encode(r_type, data))?;
+
+    Ok(())
+  }
+
+  /**
+   * Read until the next frame, answering pings along the way
+   */
+  fn read_message(&mut self) -> Result<Value, Error> {
+    let mut chunk = [0; 4096];
+
+    loop {
+      let packet = match self.codec.decode() {
+        Ok(packet) => packet,
+        Err(FrameError::Io(err)) => return Err(Error::Io(err)),
+        Err(err) => return Err(Error::InvalidFrame(err.to_string())),
+      };
+
+      let Some((r_type, message)) = packet else {
+        match self.stream.read(&mut chunk)? {
+          0 => return Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+          read => self.codec.extend(&chunk[..read]),
+        }
+        continue;
+      };
+
+      match r_type {
+        PacketType::Frame => {
+          return serde_json::from_str(&message).map_err(|err| Error::InvalidFrame(err.to_string()))
+        }
+        PacketType::Close => {
+          let value = serde_json::from_str::<Value>(&message).unwrap_or_default();
+
+          return Err(Error::IpcClosed(
+            value["code"].as_u64().unwrap_or_default() as u32,
+            value["message"].as_str().unwrap_or_default().to_string(),
+          ));
+        }
+        PacketType::Ping => self.send(PacketType::Pong, &message)?,
+        PacketType::Handshake | PacketType::Pong => (),
+      }
+    }
+  }
+}
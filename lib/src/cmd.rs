@@ -160,7 +160,7 @@ pub struct Metadata {
 }
 
 #[skip_serializing_none]
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug, Default)]
 #[serde(rename_all = "snake_case")]
 pub struct Activity {
   pub id: Option<String>,
@@ -189,7 +189,7 @@ pub struct Activity {
 
 #[skip_serializing_none]
 #[derive(Clone, Serialize, Deserialize, Debug, Default)]
-pub struct TimeoutValue(i64);
+pub struct TimeoutValue(pub i64);
 
 #[skip_serializing_none]
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -206,3 +206,146 @@ pub struct Button {
   pub label: String,
   pub url: String,
 }
+
+/// The user sent to clients in the READY dispatch, which games may show in their UI
+#[skip_serializing_none]
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct User {
+  pub id: String,
+  pub username: String,
+  pub discriminator: String,
+  pub global_name: Option<String>,
+  pub avatar: Option<String>,
+  #[serde(default)]
+  pub bot: bool,
+  #[serde(default)]
+  pub flags: u64,
+  #[serde(default)]
+  pub premium_type: u32,
+}
+
+impl Default for User {
+  fn default() -> Self {
+    Self {
+      id: "1045800378228281345".to_string(),
+      username: "arRPC".to_string(),
+      discriminator: "0000".to_string(),
+      global_name: None,
+      avatar: Some("cfefa4d9839fb4bdf030f91c2a13e95c".to_string()),
+      bot: false,
+      flags: 0,
+      premium_type: 0,
+    }
+  }
+}
+
+/// Where clients should find the API and CDN, sent in the READY dispatch
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ReadyConfig {
+  pub api_endpoint: String,
+  pub cdn_host: String,
+  pub environment: String,
+}
+
+impl Default for ReadyConfig {
+  fn default() -> Self {
+    Self {
+      api_endpoint: "//discord.com/api".to_string(),
+      cdn_host: "cdn.discordapp.com".to_string(),
+      environment: "production".to_string(),
+    }
+  }
+}
+
+/// The `data` of the READY dispatch
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Ready {
+  pub v: u32,
+  pub user: User,
+  pub config: ReadyConfig,
+}
+
+/// Where a website asked the client to navigate to, with `DEEP_LINK`
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeepLink {
+  /// A channel, or a message in one. DMs have no guild.
+  Channel {
+    guild_id: Option<String>,
+    channel_id: Option<String>,
+    message_id: Option<String>,
+  },
+  StoreHome,
+  StoreListing {
+    sku_id: Option<String>,
+    slug: Option<String>,
+  },
+  Library,
+  UserSettings {
+    section: Option<String>,
+  },
+  /// Any other type, passed along as-is
+  Other {
+    r#type: String,
+    params: Value,
+  },
+}
+
+impl DeepLink {
+  /**
+   * Parse the `{ "type": ..., "params": { ... } }` args of a DEEP_LINK command
+   */
+  pub fn from_args(args: &Value) -> Option<Self> {
+    let r#type = args["type"].as_str()?;
+    let params = &args["params"];
+    let param = |name: &str| params[name].as_str().map(str::to_string);
+
+    Some(match r#type {
+      "CHANNEL" => DeepLink::Channel {
+        guild_id: param("guildId"),
+        channel_id: param("channelId"),
+        message_id: param("messageId"),
+      },
+      "STORE_HOME" => DeepLink::StoreHome,
+      "STORE_LISTING" => DeepLink::StoreListing {
+        sku_id: param("skuId"),
+        slug: param("slug"),
+      },
+      "LIBRARY" => DeepLink::Library,
+      "USER_SETTINGS" => DeepLink::UserSettings {
+        section: param("section"),
+      },
+      _ => DeepLink::Other {
+        r#type: r#type.to_string(),
+        params: params.clone(),
+      },
+    })
+  }
+
+  /**
+   * Turn the link back into DEEP_LINK args
+   */
+  pub fn to_args(&self) -> Value {
+    let (r#type, params) = match self {
+      DeepLink::Channel {
+        guild_id,
+        channel_id,
+        message_id,
+      } => (
+        "CHANNEL",
+        serde_json::json!({ "guildId": guild_id, "channelId": channel_id, "messageId": message_id }),
+      ),
+      DeepLink::StoreHome => ("STORE_HOME", serde_json::json!({})),
+      DeepLink::StoreListing { sku_id, slug } => (
+        "STORE_LISTING",
+        serde_json::json!({ "skuId": sku_id, "slug": slug }),
+      ),
+      DeepLink::Library => ("LIBRARY", serde_json::json!({})),
+      DeepLink::UserSettings { section } => {
+        ("USER_SETTINGS", serde_json::json!({ "section": section }))
+      }
+      DeepLink::Other { r#type, params } => (r#type.as_str(), params.clone()),
+    };
+
+    serde_json::json!({ "type": r#type, "params": params })
+  }
+}
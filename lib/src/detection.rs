@@ -55,7 +55,8 @@ pub struct DetectableActivity {
   #[serde(default)]
   pub tags: Option<Vec<String>>,
   pub pid: Option<u64>,
-  pub timestamp: Option<String>,
+  /// When the detected process was started, in milliseconds since the Unix epoch
+  pub timestamp: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -74,6 +75,38 @@ pub struct Executable {
   pub arguments: Option<String>,
 }
 
+/// The `Executable::os` value for the platform we were built for
+#[cfg(target_os = "windows")]
+pub const CURRENT_OS: &str = "win32";
+#[cfg(target_os = "macos")]
+pub const CURRENT_OS: &str = "darwin";
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+pub const CURRENT_OS: &str = "linux";
+
+/// Which executables the process scanner tries to match, based on their `os`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExecutableOsFilter {
+  /// Only executables for the current OS
+  CurrentOs,
+  /// Executables for the current OS, plus Windows ones for games running under Wine/Proton
+  #[default]
+  CurrentOsAndWin32,
+  /// Every executable, regardless of OS
+  All,
+}
+
+impl ExecutableOsFilter {
+  pub fn allows(&self, executable: &Executable) -> bool {
+    match self {
+      ExecutableOsFilter::CurrentOs => executable.os == CURRENT_OS,
+      ExecutableOsFilter::CurrentOsAndWin32 => {
+        executable.os == CURRENT_OS || executable.os == "win32"
+      }
+      ExecutableOsFilter::All => true,
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Publisher {
   pub id: String,
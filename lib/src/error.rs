@@ -0,0 +1,57 @@
+use std::{fmt, ops::Range};
+
+#[derive(Debug)]
+pub enum Error {
+  /// The detectable activities list could not be parsed
+  InvalidDetectables(serde_json::Error),
+  /// Reading a file or setting up a socket failed
+  Io(std::io::Error),
+  /// A websocket server could not be bound to the given port
+  PortInUse(u16),
+  /// None of the ports in the given range could be bound
+  NoAvailablePort(Range<u16>),
+  /// A setting in `RPCConfig` can't work, with what is wrong with it
+  InvalidConfig(String),
+  /// No IPC socket could be created or connected to, contains the last error encountered
+  IpcSocketUnavailable(std::io::Error),
+  /// The other end of an IPC connection sent a Close, with its code and message
+  IpcClosed(u32, String),
+  /// The other end of an IPC connection sent something that isn't a valid frame or payload
+  InvalidFrame(String),
+  /// `start()` was called on something that is already running
+  AlreadyStarted,
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Error::InvalidDetectables(err) => write!(f, "invalid detectables JSON: {err}"),
+      Error::Io(err) => write!(f, "I/O error: {err}"),
+      Error::PortInUse(port) => write!(f, "port {port} is already in use"),
+      Error::NoAvailablePort(ports) => {
+        write!(f, "no available port in {}..{}", ports.start, ports.end)
+      }
+      Error::InvalidConfig(reason) => write!(f, "invalid config: {reason}"),
+      Error::IpcSocketUnavailable(err) => write!(f, "IPC socket unavailable: {err}"),
+      Error::IpcClosed(code, message) => write!(f, "IPC connection closed ({code}): {message}"),
+      Error::InvalidFrame(reason) => write!(f, "invalid IPC frame: {reason}"),
+      Error::AlreadyStarted => write!(f, "server is already started"),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::InvalidDetectables(err) => Some(err),
+      Error::Io(err) | Error::IpcSocketUnavailable(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from(err: std::io::Error) -> Self {
+    Error::Io(err)
+  }
+}
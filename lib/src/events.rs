@@ -0,0 +1,146 @@
+use std::sync::{mpsc, Arc, Mutex};
+
+use serde_json::Value;
+
+use crate::cmd::{Activity, DeepLink};
+
+pub type EventCallback = dyn FnMut(RPCEvent) + Send + Sync;
+
+/// Where an activity came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActivitySource {
+  /// A game talking to us over the `discord-ipc-N` socket/pipe
+  Ipc,
+  /// A website or app talking to us over the RPC websocket
+  Websocket,
+  /// A game found by the process scanner
+  Process,
+}
+
+/// What became of an `InviteBrowser` request
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InviteResponse {
+  /// The user was shown the invite and joined, or can join
+  Accepted,
+  /// The user dismissed the invite
+  Rejected,
+  /// The invite doesn't exist or has expired
+  Invalid,
+}
+
+#[derive(Clone, Debug)]
+pub enum RPCEvent {
+  /// An activity was set or updated. `socket_id` identifies the activity until it is cleared.
+  ActivitySet {
+    source: ActivitySource,
+    socket_id: String,
+    pid: u64,
+    activity: Box<Activity>,
+  },
+  /// The activity previously set with this `socket_id` should be removed
+  ActivityCleared {
+    source: ActivitySource,
+    socket_id: String,
+    pid: u64,
+  },
+  /// A website asked the client to open a guild invite. The website waits until it is answered with
+  /// `RPCServer::respond_to_invite()` (or by a bridge client), or times out.
+  InviteBrowser { request_id: u64, code: String },
+  /// A website asked the client to navigate somewhere, like a channel or store page
+  DeepLink(DeepLink),
+  /// A website (like discord.new) asked the client to create a guild from a template
+  GuildTemplateBrowser { code: String },
+  /// An account connection's OAuth flow finished, and the client should complete it
+  ConnectionsCallback {
+    provider_type: String,
+    code: String,
+    state: Option<String>,
+    open_id_params: Option<Value>,
+  },
+  /// An application connected (and, for IPC, completed its handshake)
+  ClientConnected {
+    source: ActivitySource,
+    client_id: String,
+  },
+}
+
+/**
+ * Fans events out to every channel subscriber and callback
+ */
+#[derive(Clone, Default)]
+pub(crate) struct EventBus {
+  senders: Arc<Mutex<Vec<mpsc::Sender<RPCEvent>>>>,
+  callbacks: Arc<Mutex<Vec<Arc<Mutex<EventCallback>>>>>,
+}
+
+impl EventBus {
+  pub fn subscribe(&self) -> mpsc::Receiver<RPCEvent> {
+    let (sender, receiver) = mpsc::channel();
+    self.senders.lock().unwrap().push(sender);
+    receiver
+  }
+
+  pub fn add_callback(&self, callback: Arc<Mutex<EventCallback>>) {
+    self.callbacks.lock().unwrap().push(callback);
+  }
+
+  pub fn emit(&self, event: RPCEvent) {
+    // Drop subscribers whose receiver is gone
+    self
+      .senders
+      .lock()
+      .unwrap()
+      .retain(|sender| sender.send(event.clone()).is_ok());
+
+    // Called with the list unlocked, so callbacks can subscribe or add more callbacks themselves
+    let callbacks = self.callbacks.lock().unwrap().clone();
+
+    for callback in callbacks {
+      callback.lock().unwrap()(event.clone());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{thread, time::Duration};
+
+  fn connected() -> RPCEvent {
+    RPCEvent::ClientConnected {
+      source: ActivitySource::Ipc,
+      client_id: "1234567890".to_string(),
+    }
+  }
+
+  #[test]
+  fn callbacks_can_use_the_bus_they_are_called_from() {
+    let bus = EventBus::default();
+    let (seen_sender, seen) = mpsc::channel();
+
+    let inner = bus.clone();
+    bus.add_callback(Arc::new(Mutex::new(move |_| {
+      let _receiver = inner.subscribe();
+
+      let seen_sender = seen_sender.clone();
+      inner.add_callback(Arc::new(Mutex::new(move |event| {
+        let _ = seen_sender.send(event);
+      })));
+    })));
+
+    // Emit from another thread, so a deadlock fails the test instead of hanging it
+    let (done_sender, done) = mpsc::channel();
+    let emitter = bus.clone();
+    thread::spawn(move || {
+      emitter.emit(connected());
+      emitter.emit(connected());
+      let _ = done_sender.send(());
+    });
+
+    assert!(done.recv_timeout(Duration::from_secs(5)).is_ok());
+
+    // Callbacks added during an emit only see the events after it
+    assert_eq!(seen.try_iter().count(), 1);
+  }
+}
@@ -1,22 +1,36 @@
-use detection::DetectableActivity;
+use cmd::{ReadyConfig, User};
+use detection::{DetectableActivity, ExecutableOsFilter};
+use events::{EventBus, InviteResponse, RPCEvent};
+use process_source::{ProcessSource, SystemProcessSource};
 use server::{
   client_connector::ClientConnector,
+  invites::PendingInvites,
   ipc::IpcConnector,
   ipc_utils::IpcFacilitator,
   process::{ProcessEventListeners, ProcessScanState, ProcessServer},
+  utils::ReadyPayload,
   websocket::WebsocketConnector,
 };
 use std::{
+  net::{IpAddr, Ipv4Addr},
+  ops::Range,
   path::PathBuf,
   sync::{mpsc, Arc, Mutex},
+  time::Duration,
 };
 
+pub mod client;
 pub mod cmd;
 pub mod detection;
+mod error;
+pub mod events;
 mod logger;
+pub mod process_source;
 mod server;
 mod url_params;
 
+pub use error::Error;
+
 pub type ProcessCallback = dyn FnMut(ProcessScanState) + Send + Sync;
 
 #[derive(Clone, Debug)]
@@ -25,6 +39,46 @@ pub struct RPCConfig {
   pub enable_ipc_connector: bool,
   pub enable_websocket_connector: bool,
   pub enable_secondary_events: bool,
+  /// Run the arRPC-compatible websocket bridge. Not needed when consuming events via `subscribe()`/`on_event()`.
+  pub enable_bridge: bool,
+
+  /// Port the bridge websocket listens on
+  pub bridge_port: u16,
+  /// Ports to try, in order, for the RPC websocket server
+  pub websocket_ports: Range<u16>,
+  /// Address both websocket servers bind to
+  pub bind_address: IpAddr,
+  /// Which origins may connect to the RPC websocket server
+  pub websocket_origin_policy: OriginPolicy,
+  /// How long a website's INVITE_BROWSER waits to be answered before it gets an error
+  pub invite_timeout: Duration,
+  /// Directory to create IPC sockets in. When unset, `$XDG_RUNTIME_DIR`, `$TMPDIR`, `$TMP`, `$TEMP` or `/tmp` is
+  /// used. Ignored on Windows, where named pipes always live under `\\.\pipe\`.
+  pub ipc_socket_dir: Option<PathBuf>,
+  /// Name of the IPC socket, before the `-N` suffix
+  pub ipc_socket_prefix: String,
+  /// How many `discord-ipc-N` slots to listen on, from the lowest free ones (1 to 10). Some SDKs only try slot 0,
+  /// others go through them in order.
+  pub ipc_slot_count: u8,
+  /// When set and slot 0 was taken by something else, keep trying to claim it at this interval
+  pub ipc_reprobe_interval: Option<Duration>,
+  /// Also listen in the Flatpak and Snap locations under `$XDG_RUNTIME_DIR`, so sandboxed games and clients can
+  /// reach the IPC sockets. Where a socket can't be created, a symlink is tried instead. Linux only.
+  pub ipc_sandbox_links: bool,
+  /// Largest IPC frame, in bytes, a client may send. Clients going over it are disconnected.
+  pub ipc_max_frame_size: usize,
+
+  /// Time between process scans
+  pub process_scan_interval: Duration,
+  /// Scan faster or slower than `process_scan_interval` depending on what is going on. Off when unset.
+  pub adaptive_process_scan: Option<AdaptiveScanConfig>,
+  /// Which detectable executables to match against, based on the OS they are listed for
+  pub executable_os_filter: ExecutableOsFilter,
+
+  /// User sent to clients in the READY dispatch. Can be changed later with `RPCServer::set_user()`.
+  pub user: User,
+  /// API endpoint, CDN host and environment sent to clients in the READY dispatch
+  pub ready_config: ReadyConfig,
 }
 
 impl Default for RPCConfig {
@@ -34,43 +88,131 @@ impl Default for RPCConfig {
       enable_ipc_connector: true,
       enable_websocket_connector: true,
       enable_secondary_events: true,
+      enable_bridge: true,
+
+      bridge_port: 1337,
+      websocket_ports: 6463..6472,
+      bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+      websocket_origin_policy: OriginPolicy::default(),
+      invite_timeout: Duration::from_secs(30),
+      ipc_socket_dir: None,
+      ipc_socket_prefix: "discord-ipc".to_string(),
+      ipc_slot_count: 1,
+      ipc_reprobe_interval: None,
+      ipc_sandbox_links: false,
+      ipc_max_frame_size: server::ipc_utils::DEFAULT_MAX_FRAME_SIZE,
+
+      process_scan_interval: Duration::from_secs(10),
+      adaptive_process_scan: None,
+      executable_os_filter: ExecutableOsFilter::default(),
+
+      user: User::default(),
+      ready_config: ReadyConfig::default(),
     }
   }
 }
 
+#[derive(Clone, Debug)]
+pub struct AdaptiveScanConfig {
+  /// Interval used for a while after a game starts or stops
+  pub fast_interval: Duration,
+  /// How long to keep scanning at `fast_interval`
+  pub fast_period: Duration,
+  /// Interval used while on battery (only detected on Linux) or idle
+  pub slow_interval: Duration,
+  /// Consider the system idle once no game has started or stopped for this long
+  pub idle_after: Duration,
+}
+
+impl Default for AdaptiveScanConfig {
+  fn default() -> Self {
+    Self {
+      fast_interval: Duration::from_secs(2),
+      fast_period: Duration::from_secs(30),
+      slow_interval: Duration::from_secs(30),
+      idle_after: Duration::from_secs(300),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub struct OriginPolicy {
+  /// Origins allowed to connect, e.g. `https://discord.com`. Add your own for Discord forks and self-hosted clients.
+  pub allowed_origins: Vec<String>,
+  /// Reject clients that send no Origin header at all. Games and SDKs connecting directly usually don't send one.
+  pub require_origin: bool,
+}
+
+impl Default for OriginPolicy {
+  fn default() -> Self {
+    Self {
+      allowed_origins: vec![
+        "https://discord.com".to_string(),
+        "https://canary.discord.com".to_string(),
+        "https://ptb.discord.com".to_string(),
+      ],
+      require_origin: false,
+    }
+  }
+}
+
+impl OriginPolicy {
+  pub fn allows(&self, origin: Option<&str>) -> bool {
+    let Some(origin) = origin else {
+      return !self.require_origin;
+    };
+
+    let origin = origin.trim_end_matches('/');
+
+    self
+      .allowed_origins
+      .iter()
+      .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+  }
+}
+
 #[derive(Clone)]
 pub struct Connectors {
   process_server: Arc<Mutex<ProcessServer>>,
   client_connector: Arc<Mutex<ClientConnector>>,
-  ipc_connector: Arc<Mutex<IpcConnector>>,
-  ws_connector: Arc<Mutex<WebsocketConnector>>,
+  /// Only created when enabled, so disabled connectors don't hold on to sockets or ports
+  ipc_connector: Option<Arc<Mutex<IpcConnector>>>,
+  ws_connector: Option<Arc<Mutex<WebsocketConnector>>>,
 }
 
 pub struct RPCServer {
   detectable: Arc<Mutex<Vec<Arc<DetectableActivity>>>>,
   connectors: Option<Connectors>,
   config: RPCConfig,
+  events: EventBus,
+  ready: ReadyPayload,
+  invites: PendingInvites,
+  process_source: Arc<Mutex<Box<dyn ProcessSource>>>,
 
   on_process_scan_complete: Option<Arc<Mutex<ProcessCallback>>>,
 }
 
 impl RPCServer {
-  pub fn from_json_str(
-    detectable: impl AsRef<str>,
-    config: RPCConfig,
-  ) -> Result<Self, Box<dyn std::error::Error>> {
-    // Parse as DetectableActivity vector, panic if invalid
+  pub fn from_json_str(detectable: impl AsRef<str>, config: RPCConfig) -> Result<Self, Error> {
+    // Parse as DetectableActivity vector
     let detectable: Vec<DetectableActivity> =
-      serde_json::from_str(detectable.as_ref()).expect("Invalid JSON provided to RPCServer");
+      serde_json::from_str(detectable.as_ref()).map_err(Error::InvalidDetectables)?;
 
     let detectable: Vec<Arc<DetectableActivity>> = detectable.into_iter().map(Arc::new).collect();
 
+    let ready = ReadyPayload::new(config.user.clone(), config.ready_config.clone());
+    let invites = PendingInvites::new(config.invite_timeout);
+
     Ok(Self {
       detectable: Arc::new(Mutex::new(detectable)),
 
       // Default to empty servers
       connectors: None,
       config,
+      events: EventBus::default(),
+      ready,
+      invites,
+      process_source: Arc::new(Mutex::new(Box::new(SystemProcessSource::new()))),
 
       // Event listeners
       on_process_scan_complete: None,
@@ -80,10 +222,9 @@ impl RPCServer {
   /**
    * Create a new RPCServer and read the detectable games list from file.
    */
-  pub fn from_file(file: PathBuf, config: RPCConfig) -> Result<Self, Box<dyn std::error::Error>> {
+  pub fn from_file(file: PathBuf, config: RPCConfig) -> Result<Self, Error> {
     // Read the detectable games list from file.
-    let detectable = std::fs::read_to_string(&file)
-      .unwrap_or_else(|_| panic!("RPCServer could not find file: {:?}", file.display()));
+    let detectable = std::fs::read_to_string(&file)?;
 
     Self::from_json_str(detectable.as_str(), config)
   }
@@ -127,7 +268,8 @@ impl RPCServer {
   }
 
   /**
-   * Manually trigger a scan for processes. This should be run AFTER start().
+   * Manually trigger a scan for processes. This should be run AFTER start(). The next automatic scan is pushed
+   * back by a full interval, so the two don't double up.
    */
   pub fn scan_for_processes(&mut self) {
     if self.connectors.is_none() {
@@ -135,20 +277,14 @@ impl RPCServer {
       return;
     }
 
-    let process_server = self
+    self
       .connectors
       .as_mut()
       .unwrap()
       .process_server
       .lock()
-      .unwrap();
-
-    match process_server.scan_for_processes() {
-      Ok(_) => {}
-      Err(err) => {
-        log!("[RPC Server] Error while scanning processes: {}", err);
-      }
-    }
+      .unwrap()
+      .scan_now();
   }
 
   pub fn on_process_scan_complete(
@@ -163,7 +299,99 @@ impl RPCServer {
     self.on_process_scan_complete = Some(Arc::new(Mutex::new(callback)));
   }
 
-  pub fn start(&mut self) {
+  /**
+   * Replace where the process scanner gets its process list from. This should be run BEFORE start().
+   */
+  pub fn set_process_source(&mut self, source: impl ProcessSource + 'static) {
+    if self.connectors.is_some() {
+      log!("[RPC Server] Cannot set process source, connectors are already initialized");
+      return;
+    }
+
+    self.process_source = Arc::new(Mutex::new(Box::new(source)));
+  }
+
+  /**
+   * Tell the website behind an `RPCEvent::InviteBrowser` what became of its invite. Returns false if the request
+   * was already answered or timed out.
+   */
+  pub fn respond_to_invite(&self, request_id: u64, response: InviteResponse) -> bool {
+    self.invites.resolve(request_id, response)
+  }
+
+  /**
+   * Change the user sent to clients in the READY dispatch, e.g. once your own client has logged in. Clients that
+   * are already connected keep the user they got.
+   */
+  pub fn set_user(&self, user: User) {
+    self.ready.set_user(user);
+  }
+
+  /**
+   * Which `discord-ipc-N` slots the IPC connector is listening on. Empty when not started.
+   */
+  pub fn ipc_slots(&self) -> Vec<u8> {
+    match self
+      .connectors
+      .as_ref()
+      .and_then(|connectors| connectors.ipc_connector.as_ref())
+    {
+      Some(ipc_connector) => ipc_connector.lock().unwrap().slots(),
+      None => vec![],
+    }
+  }
+
+  /**
+   * Send an event, like `ACTIVITY_JOIN` with `{ "secret": "..." }`, to the connection that set the activity with
+   * this `socket_id`. Only connections that subscribed to `evt` receive it. Returns whether any connection did.
+   */
+  pub fn dispatch_event(&self, socket_id: &str, evt: &str, data: serde_json::Value) -> bool {
+    let Some(connectors) = &self.connectors else {
+      log!("[RPC Server] Cannot dispatch event, connectors are not initialized");
+      return false;
+    };
+
+    let ipc_sent = connectors
+      .ipc_connector
+      .as_ref()
+      .is_some_and(|ipc_connector| {
+        ipc_connector
+          .lock()
+          .unwrap()
+          .connections()
+          .dispatch(socket_id, evt, &data)
+      });
+    let ws_sent = connectors
+      .ws_connector
+      .as_ref()
+      .is_some_and(|ws_connector| ws_connector.lock().unwrap().dispatch(socket_id, evt, &data));
+
+    ipc_sent || ws_sent
+  }
+
+  /**
+   * Receive every activity event. Subscriptions survive `stop()` and `start()`, and can be made at any time.
+   */
+  pub fn subscribe(&self) -> mpsc::Receiver<RPCEvent> {
+    self.events.subscribe()
+  }
+
+  /**
+   * Callback variant of `subscribe()`. The callback runs on the connector threads, so keep it short.
+   */
+  pub fn on_event(&mut self, callback: impl FnMut(RPCEvent) + Send + Sync + 'static) {
+    self.events.add_callback(Arc::new(Mutex::new(callback)));
+  }
+
+  /**
+   * Create and start every enabled connector. If anything fails to bind, whatever was already created is
+   * torn down again before the error is returned.
+   */
+  pub fn start(&mut self) -> Result<(), Error> {
+    if self.connectors.is_some() {
+      return Err(Error::AlreadyStarted);
+    }
+
     let (proc_event_sender, proc_event_receiver) = mpsc::channel();
     let (ipc_event_sender, ipc_event_receiver) = mpsc::channel();
     let (ws_event_sender, ws_event_reciever) = mpsc::channel();
@@ -175,29 +403,83 @@ impl RPCServer {
         ProcessEventListeners {
           on_process_scan_complete: self.on_process_scan_complete.clone(),
         },
+        self.process_source.clone(),
+        self.config.process_scan_interval,
+        self.config.adaptive_process_scan.clone(),
+        self.config.executable_os_filter,
       ))),
       client_connector: Arc::new(Mutex::new(ClientConnector::new(
-        1337,
-        server::utils::CONNECTION_REPONSE.to_string(),
+        self.config.bind_address,
+        self.config.bridge_port,
+        self.config.enable_bridge,
+        self.ready.clone(),
+        self.invites.clone(),
+        self.events.clone(),
         ipc_event_receiver,
         proc_event_receiver,
         ws_event_reciever,
-      ))),
-      ipc_connector: Arc::new(Mutex::new(IpcConnector::new(ipc_event_sender))),
-      ws_connector: Arc::new(Mutex::new(WebsocketConnector::new(ws_event_sender))),
+      )?)),
+      ipc_connector: if self.config.enable_ipc_connector {
+        Some(Arc::new(Mutex::new(IpcConnector::new(
+          ipc_event_sender,
+          self.events.clone(),
+          self.ready.clone(),
+          self.config.ipc_socket_dir.as_deref(),
+          &self.config.ipc_socket_prefix,
+          self.config.ipc_slot_count,
+          self.config.ipc_reprobe_interval,
+          self.config.ipc_sandbox_links,
+          self.config.ipc_max_frame_size,
+        )?)))
+      } else {
+        None
+      },
+      // Secondary events (invites, deep links, ...) come in over the websocket too
+      ws_connector: if self.config.enable_websocket_connector || self.config.enable_secondary_events
+      {
+        Some(Arc::new(Mutex::new(WebsocketConnector::new(
+          ws_event_sender,
+          self.events.clone(),
+          self.ready.clone(),
+          self.config.websocket_origin_policy.clone(),
+          self.invites.clone(),
+          self.config.bind_address,
+          self.config.websocket_ports.clone(),
+        )?)))
+      } else {
+        None
+      },
     };
 
-    log!(
-      "[RPC Server] Starting client connector on port {}...",
-      connectors.client_connector.lock().unwrap().port
-    );
-    connectors.client_connector.lock().unwrap().start();
+    self.connectors = Some(connectors.clone());
+
+    if let Err(err) = self.start_connectors(&connectors) {
+      log!("[RPC Server] Failed to start: {}", err);
+      self.stop();
+      return Err(err);
+    }
 
-    let config = self.config.clone();
+    log!("[RPC Server] Done! Watching for activity...");
 
-    if config.enable_ipc_connector {
+    Ok(())
+  }
+
+  fn start_connectors(&self, connectors: &Connectors) -> Result<(), Error> {
+    if self.config.enable_bridge {
+      log!(
+        "[RPC Server] Starting client connector on port {}...",
+        connectors.client_connector.lock().unwrap().port
+      );
+    } else {
+      log!("[RPC Server] Starting client connector without bridge...");
+    }
+    connectors.client_connector.lock().unwrap().start()?;
+
+    let config = &self.config;
+
+    if let Some(ipc_connector) = &connectors.ipc_connector {
       log!("[RPC Server] Starting IPC connector...");
-      connectors.ipc_connector.lock().unwrap().start();
+      ipc_connector.lock().unwrap().start();
     }
 
     if config.enable_process_scanner {
@@ -205,15 +487,44 @@ impl RPCServer {
       connectors.process_server.lock().unwrap().start();
     }
 
-    if config.enable_websocket_connector || config.enable_secondary_events {
+    if let Some(ws_connector) = &connectors.ws_connector {
       log!("[RPC Server] Starting websocket connector...");
-      connectors.ws_connector.lock().unwrap().start(
+      ws_connector.lock().unwrap().start(
         config.enable_websocket_connector,
         config.enable_secondary_events,
-      );
+      )?;
     }
 
-    log!("[RPC Server] Done! Watching for activity...");
-    self.connectors = Some(connectors);
+    Ok(())
+  }
+
+  /**
+   * Stop every connector and wait for all of their threads to exit. The IPC socket is unbound and the
+   * websocket servers are closed by the time this returns, so the server can be started again afterwards.
+   */
+  pub fn stop(&mut self) {
+    let Some(connectors) = self.connectors.take() else {
+      return;
+    };
+
+    log!("[RPC Server] Stopping...");
+
+    // Stop everything that produces events first, so the client connector can clear activities on the way out
+    connectors.process_server.lock().unwrap().stop();
+    if let Some(ipc_connector) = &connectors.ipc_connector {
+      ipc_connector.lock().unwrap().stop();
+    }
+    if let Some(ws_connector) = &connectors.ws_connector {
+      ws_connector.lock().unwrap().stop();
+    }
+    connectors.client_connector.lock().unwrap().stop();
+
+    log!("[RPC Server] Stopped");
+  }
+}
+
+impl Drop for RPCServer {
+  fn drop(&mut self) {
+    self.stop();
   }
 }
@@ -0,0 +1,218 @@
+use std::sync::{Arc, Mutex};
+
+#[cfg(not(target_os = "linux"))]
+use sysinfo::System;
+
+/// A running process, as far as detection is concerned
+#[derive(Clone, Debug, Default)]
+pub struct Exec {
+  pub pid: u64,
+  pub path: String,
+  pub arguments: Option<String>,
+  /// When the process was started, in milliseconds since the Unix epoch
+  pub start_time: Option<u64>,
+  /// Owner of the process, where the platform has such a thing
+  pub uid: Option<u32>,
+}
+
+/**
+ * Something that can list the processes currently running, for the process scanner to match against
+ */
+pub trait ProcessSource: Send {
+  fn processes(&mut self) -> Result<Vec<Exec>, Box<dyn std::error::Error>>;
+}
+
+/**
+ * The default source: `/proc` on Linux, sysinfo everywhere else
+ */
+#[derive(Default)]
+pub struct SystemProcessSource {
+  #[cfg(not(target_os = "linux"))]
+  sysinfo: System,
+}
+
+impl SystemProcessSource {
+  pub fn new() -> Self {
+    Self::default()
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl ProcessSource for SystemProcessSource {
+  fn processes(&mut self) -> Result<Vec<Exec>, Box<dyn std::error::Error>> {
+    use std::path::Path;
+    use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, UpdateKind};
+
+    let mut processes = Vec::new();
+    let sys = &mut self.sysinfo;
+    sys.refresh_processes_specifics(
+      ProcessesToUpdate::All,
+      true,
+      ProcessRefreshKind::nothing()
+        .with_exe(UpdateKind::OnlyIfNotSet)
+        .with_cmd(UpdateKind::OnlyIfNotSet)
+        .with_user(UpdateKind::OnlyIfNotSet),
+    );
+
+    for proc in sys.processes() {
+      let mut cmd = proc.1.cmd().iter();
+      let start_time = proc.1.start_time();
+
+      #[cfg(unix)]
+      let uid = proc.1.user_id().map(|uid| **uid);
+      #[cfg(not(unix))]
+      let uid = None;
+
+      processes.push(Exec {
+        pid: proc.0.to_string().parse::<u64>()?,
+        path: proc.1.exe().unwrap_or(Path::new("")).display().to_string(),
+        arguments: cmd.next().map(|_| {
+          cmd
+            .map(|x| x.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+        }),
+        start_time: if start_time > 0 {
+          Some(start_time * 1000)
+        } else {
+          None
+        },
+        uid,
+      });
+    }
+
+    Ok(processes)
+  }
+}
+
+#[cfg(target_os = "linux")]
+impl ProcessSource for SystemProcessSource {
+  fn processes(&mut self) -> Result<Vec<Exec>, Box<dyn std::error::Error>> {
+    use std::fs;
+    use std::os::unix::fs::MetadataExt;
+
+    let proc_list = fs::read_dir("/proc")?.filter(|e| {
+      if let Ok(entry) = e {
+        // Only if we can parse this as a number
+        return entry.file_name().to_str().unwrap().parse::<u64>().is_ok();
+      }
+
+      false
+    });
+    let mut processes = Vec::new();
+    let boot_time = boot_time();
+    let clock_ticks = clock_ticks_per_second();
+
+    for entry in proc_list {
+      let entry = entry?;
+      let path = entry.path();
+
+      if let Ok(cmdline) = fs::read_to_string(path.join("cmdline")) {
+        if !cmdline.is_empty() {
+          let mut cmd_iter = cmdline.split('\0');
+          let (cmd_path, cmd_args) = (
+            cmd_iter.next().unwrap_or("").to_string(),
+            cmd_iter.collect::<Vec<_>>().join(" "),
+          );
+          processes.push(Exec {
+            pid: path
+              .file_name()
+              .ok_or("Invalid path")?
+              .to_str()
+              .ok_or("Invalid path")?
+              .parse::<u64>()?,
+            path: cmd_path,
+            arguments: if cmd_args.is_empty() {
+              None
+            } else {
+              Some(cmd_args)
+            },
+            start_time: boot_time
+              .zip(clock_ticks)
+              .and_then(|(boot_time, clock_ticks)| {
+                process_start_time(&path, boot_time, clock_ticks)
+              }),
+            // /proc/<pid> is owned by whoever owns the process
+            uid: entry.metadata().ok().map(|metadata| metadata.uid()),
+          });
+        }
+      }
+    }
+
+    Ok(processes)
+  }
+}
+
+/**
+ * Read the system boot time from /proc/stat, in seconds since the Unix epoch
+ */
+#[cfg(target_os = "linux")]
+fn boot_time() -> Option<u64> {
+  let stat = std::fs::read_to_string("/proc/stat").ok()?;
+
+  stat
+    .lines()
+    .find_map(|line| line.strip_prefix("btime "))
+    .and_then(|btime| btime.trim().parse::<u64>().ok())
+}
+
+/**
+ * How many clock ticks (USER_HZ) make a second, which is what /proc reports times in
+ */
+#[cfg(target_os = "linux")]
+fn clock_ticks_per_second() -> Option<u64> {
+  let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+
+  // -1 if it can't be determined, in which case start times are left unknown
+  (ticks > 0).then_some(ticks as u64)
+}
+
+/**
+ * Read when a process started from /proc/<pid>/stat, in milliseconds since the Unix epoch
+ */
+#[cfg(target_os = "linux")]
+fn process_start_time(
+  proc_path: &std::path::Path,
+  boot_time: u64,
+  clock_ticks: u64,
+) -> Option<u64> {
+  let stat = std::fs::read_to_string(proc_path.join("stat")).ok()?;
+
+  // The process name (field 2) is in parentheses and may contain spaces, so start after the last ')'.
+  // starttime is field 22, which makes it the 20th field after the name
+  let start_ticks = stat
+    .get(stat.rfind(')')? + 1..)?
+    .split_whitespace()
+    .nth(19)?
+    .parse::<u64>()
+    .ok()?;
+
+  Some(boot_time * 1000 + start_ticks * 1000 / clock_ticks)
+}
+
+/**
+ * An in-memory source that returns whatever processes it was last given. Clones share the same list, so keep
+ * one around to change what is "running" after handing it to the server.
+ */
+#[derive(Clone, Default)]
+pub struct FakeProcessSource {
+  processes: Arc<Mutex<Vec<Exec>>>,
+}
+
+impl FakeProcessSource {
+  pub fn new(processes: Vec<Exec>) -> Self {
+    Self {
+      processes: Arc::new(Mutex::new(processes)),
+    }
+  }
+
+  pub fn set_processes(&self, processes: Vec<Exec>) {
+    *self.processes.lock().unwrap() = processes;
+  }
+}
+
+impl ProcessSource for FakeProcessSource {
+  fn processes(&mut self) -> Result<Vec<Exec>, Box<dyn std::error::Error>> {
+    Ok(self.processes.lock().unwrap().clone())
+  }
+}
@@ -1,18 +1,47 @@
 use std::{
   collections::HashMap,
-  sync::{Arc, Mutex},
+  net::IpAddr,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc::{Receiver, RecvTimeoutError},
+    Arc, Mutex,
+  },
+  thread::JoinHandle,
+  time::Duration,
 };
 
 use serde::Serialize;
+use serde_json::Value;
 use serde_with::skip_serializing_none;
-use simple_websockets::{Event, EventHub, Message, Responder};
+use simple_websockets::{Event, Message, Responder};
 
 use crate::{
-  cmd::{ActivityCmd, ActivityPayload},
-  log,
+  cmd::{
+    Activity, ActivityCmd, ActivityCmdArgs, ActivityPayload, Metadata, TimeoutValue, Timestamps,
+  },
+  events::{ActivitySource, EventBus, InviteResponse, RPCEvent},
+  log, Error,
 };
 
-use super::process::ProcessDetectedEvent;
+use super::{
+  invites::PendingInvites,
+  process::ProcessDetectedEvent,
+  utils::{join_all, ReadyPayload},
+  ws_server::WebsocketServer,
+};
+
+/**
+ * Receive the next event, giving up once the connector has been told to stop and everything queued was handled
+ */
+fn recv_while_running<T>(receiver: &Receiver<T>, running: &AtomicBool) -> Option<T> {
+  loop {
+    match receiver.recv_timeout(Duration::from_millis(100)) {
+      Ok(event) => return Some(event),
+      Err(RecvTimeoutError::Timeout) if running.load(Ordering::Relaxed) => continue,
+      Err(_) => return None,
+    }
+  }
+}
 
 fn empty_activity(pid: u64, socket_id: String) -> String {
   format!(
@@ -26,15 +55,44 @@ fn empty_activity(pid: u64, socket_id: String) -> String {
   )
 }
 
+/**
+ * A bridge client answering an INVITE_BROWSER event, as
+ * `{ "cmd": "INVITE_BROWSER", "nonce": "<nonce of the event>", "data": { "result": "accepted" } }`, where the result
+ * is `accepted`, `rejected` or `invalid`
+ */
+fn invite_response(message: &Message) -> Option<(u64, InviteResponse)> {
+  let Message::Text(text) = message else {
+    return None;
+  };
+
+  let value = serde_json::from_str::<Value>(text).ok()?;
+
+  if value["cmd"] != "INVITE_BROWSER" {
+    return None;
+  }
+
+  let request_id = value["nonce"].as_str()?.parse().ok()?;
+  let response = serde_json::from_value(value["data"]["result"].clone()).ok()?;
+
+  Some((request_id, response))
+}
+
+/**
+ * Turns the raw messages from every connector into `RPCEvent`s, and optionally runs the arRPC-compatible
+ * websocket bridge (as just another subscriber of those events)
+ */
 #[derive(Clone)]
 pub struct ClientConnector {
   pub port: u16,
-  server: Arc<Mutex<Option<EventHub>>>,
+  enable_bridge: bool,
+  server: Arc<Mutex<Option<WebsocketServer>>>,
   pub clients: Arc<Mutex<HashMap<u64, Responder>>>,
-  data_on_connect: String,
-
-  pub last_pid: Option<u64>,
-  pub active_socket: Option<String>,
+  ready: ReadyPayload,
+  pending_invites: PendingInvites,
+  events: EventBus,
+  bridge_events: Arc<Mutex<Option<Receiver<RPCEvent>>>>,
+  running: Arc<AtomicBool>,
+  threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
 
   pub ipc_event_rec: Arc<Mutex<Option<std::sync::mpsc::Receiver<ActivityCmd>>>>,
   pub proc_event_rec: Arc<Mutex<Option<std::sync::mpsc::Receiver<ProcessDetectedEvent>>>>,
@@ -54,7 +112,7 @@ struct ProcessActivity {
 
 #[derive(Serialize)]
 struct ProcessTimestamps {
-  pub start: String,
+  pub start: i64,
 }
 
 #[derive(Serialize)]
@@ -65,294 +123,374 @@ struct ProcessPayload {
   pub socket_id: String,
 }
 
+/**
+ * Serialize an event into what arRPC bridge clients expect. Returns `None` for events the bridge doesn't forward.
+ */
+fn bridge_payload(event: &RPCEvent) -> Option<String> {
+  let payload = match event {
+    RPCEvent::ActivitySet {
+      source: ActivitySource::Process,
+      socket_id,
+      pid,
+      activity,
+    } => serde_json::to_string(&ProcessPayload {
+      activity: ProcessActivity {
+        application_id: activity.application_id.clone().unwrap_or_default(),
+        name: activity.name.clone().unwrap_or_default(),
+        timestamps: ProcessTimestamps {
+          start: activity
+            .timestamps
+            .as_ref()
+            .and_then(|timestamps| timestamps.start.as_ref())
+            .map(|start| start.0)
+            .unwrap_or_default(),
+        },
+        r#type: 0,
+        metadata: HashMap::new(),
+        flags: 0,
+      },
+      pid: *pid,
+      socket_id: socket_id.clone(),
+    }),
+    RPCEvent::ActivitySet {
+      socket_id,
+      pid,
+      activity,
+      ..
+    } => serde_json::to_string(&ActivityPayload {
+      activity: Some((**activity).clone()),
+      pid: Some(*pid),
+      socket_id: Some(socket_id.clone()),
+    }),
+    RPCEvent::ActivityCleared { socket_id, pid, .. } => {
+      return Some(empty_activity(*pid, socket_id.clone()))
+    }
+    // Bridge clients answer with the same nonce, see `invite_response`
+    RPCEvent::InviteBrowser { request_id, code } => serde_json::to_string(&ActivityCmd {
+      cmd: "INVITE_BROWSER".to_string(),
+      args: Some(ActivityCmdArgs {
+        pid: None,
+        activity: None,
+        code: Some(code.clone()),
+      }),
+      nonce: Value::String(request_id.to_string()),
+      ..ActivityCmd::empty()
+    }),
+    RPCEvent::DeepLink(link) => serde_json::to_string(&serde_json::json!({
+      "cmd": "DEEP_LINK",
+      "args": link.to_args(),
+      "nonce": null,
+    })),
+    RPCEvent::GuildTemplateBrowser { code } => serde_json::to_string(&serde_json::json!({
+      "cmd": "GUILD_TEMPLATE_BROWSER",
+      "args": { "code": code },
+      "nonce": null,
+    })),
+    RPCEvent::ConnectionsCallback {
+      provider_type,
+      code,
+      state,
+      open_id_params,
+    } => serde_json::to_string(&serde_json::json!({
+      "cmd": "CONNECTIONS_CALLBACK",
+      "args": {
+        "providerType": provider_type,
+        "code": code,
+        "state": state,
+        "openIdParams": open_id_params,
+      },
+      "nonce": null,
+    })),
+    RPCEvent::ClientConnected { .. } => return None,
+  };
+
+  match payload {
+    Ok(payload) => Some(payload),
+    Err(err) => {
+      log!(
+        "[Client Connector] Error serializing bridge payload: {}",
+        err
+      );
+      None
+    }
+  }
+}
+
 impl ClientConnector {
+  #[allow(clippy::too_many_arguments)]
   pub fn new(
+    address: IpAddr,
     port: u16,
-    data_on_connect: String,
+    enable_bridge: bool,
+    ready: ReadyPayload,
+    pending_invites: PendingInvites,
+    events: EventBus,
     ipc_event_rec: std::sync::mpsc::Receiver<ActivityCmd>,
     proc_event_rec: std::sync::mpsc::Receiver<ProcessDetectedEvent>,
     ws_event_rec: std::sync::mpsc::Receiver<ActivityCmd>,
-  ) -> ClientConnector {
-    ClientConnector {
-      server: Arc::new(Mutex::new(Some(
-        simple_websockets::launch(port).unwrap_or_else(|_| {
-          log!("[Client Connector] Failed to launch websocket server, port may already be in use");
-          std::process::exit(1);
-        }),
-      ))),
+  ) -> Result<ClientConnector, Error> {
+    let server = if enable_bridge {
+      Some(WebsocketServer::launch(address, port).inspect_err(|_| {
+        log!("[Client Connector] Failed to launch websocket server, port may already be in use");
+      })?)
+    } else {
+      None
+    };
+
+    // The bridge is just another subscriber, so subscribe before anything can be emitted
+    let bridge_events = if enable_bridge {
+      Some(events.subscribe())
+    } else {
+      None
+    };
+
+    Ok(ClientConnector {
+      server: Arc::new(Mutex::new(server)),
       clients: Arc::new(Mutex::new(HashMap::new())),
-      data_on_connect,
+      ready,
+      pending_invites,
       port,
-
-      last_pid: None,
-      active_socket: None,
+      enable_bridge,
+      events,
+      bridge_events: Arc::new(Mutex::new(bridge_events)),
+      running: Arc::new(AtomicBool::new(false)),
+      threads: Arc::new(Mutex::new(vec![])),
 
       ipc_event_rec: Arc::new(Mutex::new(Some(ipc_event_rec))),
       proc_event_rec: Arc::new(Mutex::new(Some(proc_event_rec))),
       ws_event_rec: Arc::new(Mutex::new(Some(ws_event_rec))),
-    }
+    })
   }
 
-  pub fn start(&mut self) {
-    let server = self
-      .server
-      .lock()
-      .unwrap()
-      .take()
-      .expect("Client connector already started");
-    let clients_clone = self.clients.clone();
-    let data_on_connect = self.data_on_connect.clone();
-
-    std::thread::spawn(move || {
-      loop {
-        match server.poll_event() {
-          Event::Connect(client_id, responder) => {
-            log!("[Client Connector] Client {} connected", client_id);
+  pub fn start(&mut self) -> Result<(), Error> {
+    if self.running.swap(true, Ordering::Relaxed) {
+      return Err(Error::AlreadyStarted);
+    }
 
-            // Send initial connection data
-            responder.send(Message::Text(data_on_connect.clone()));
+    let mut threads = self.threads.lock().unwrap();
 
-            clients_clone.lock().unwrap().insert(client_id, responder);
-          }
-          Event::Disconnect(client_id) => {
-            clients_clone.lock().unwrap().remove(&client_id);
-          }
-          Event::Message(client_id, message) => {
-            log!(
-              "[Client Connector] Received message from client {}: {:?}",
-              client_id,
-              message
-            );
-            let clients = clients_clone.lock().unwrap();
-            if let Some(responder) = clients.get(&client_id) {
-              responder.send(message);
-            }
-          }
-        }
-      }
-    });
+    if self.enable_bridge {
+      threads.push(self.start_bridge());
+    }
 
     // Create a thread for each reciever
     let ipc_event_rec = self.ipc_event_rec.lock().unwrap().take().unwrap();
     let proc_event_rec = self.proc_event_rec.lock().unwrap().take().unwrap();
     let ws_event_rec = self.ws_event_rec.lock().unwrap().take().unwrap();
 
-    let mut ipc_clone = self.clone();
-    let mut proc_clone = self.clone();
-    let mut ws_clone = self.clone();
+    let ipc_clone = self.clone();
+    let proc_clone = self.clone();
+    let ws_clone = self.clone();
 
-    std::thread::spawn(move || {
-      while let Ok(mut ipc_activity) = ipc_event_rec.recv() {
-        // if there are no client, skip
-        if ipc_clone.clients.lock().unwrap().is_empty() {
-          log!("[Client Connector] No clients connected, skipping");
-          continue;
-        }
+    let running = self.running.clone();
+    threads.push(std::thread::spawn(move || {
+      while let Some(ipc_activity) = recv_while_running(&ipc_event_rec, &running) {
+        ipc_clone.handle_activity_cmd(ActivitySource::Ipc, ipc_activity);
+      }
+    }));
 
-        ipc_activity.fix();
+    let running = self.running.clone();
+    threads.push(std::thread::spawn(move || {
+      while let Some(proc_event) = recv_while_running(&proc_event_rec, &running) {
+        match proc_event {
+          ProcessDetectedEvent::Started(proc_activity) => {
+            log!(
+              "[Client Connector] Sending payload for activity: {}",
+              proc_activity.name
+            );
 
-        let mut args = match ipc_activity.args {
-          Some(args) => args,
-          None => {
-            log!("[Client Connector] Invalid activity command, skipping");
-            continue;
+            let start = proc_activity.timestamp.unwrap_or_default() as i64;
+
+            // Every running game gets its own socket id, so they can all be shown at once
+            proc_clone.events.emit(RPCEvent::ActivitySet {
+              source: ActivitySource::Process,
+              socket_id: proc_activity.id.clone(),
+              pid: proc_activity.pid.unwrap_or_default(),
+              activity: Box::new(Activity {
+                application_id: Some(proc_activity.id.clone()),
+                name: Some(proc_activity.name.clone()),
+                timestamps: Some(Timestamps {
+                  start: Some(TimeoutValue(start)),
+                  end: None,
+                }),
+                metadata: Some(Metadata::default()),
+                flags: Some(0),
+                ..Activity::default()
+              }),
+            });
           }
-        };
-
-        if args.activity.is_none() {
-          let pid = args.pid.unwrap_or_default();
-          // Send empty payload
-          let payload = empty_activity(pid, pid.to_string());
-
-          log!("[Client Connector] Sending empty payload");
-
-          ipc_clone.send_data(payload);
-
-          continue;
-        }
-
-        let activity = args.activity.as_mut();
-
-        if let Some(activity) = activity {
-          activity.application_id = ipc_activity.application_id;
-
-          let payload = ActivityPayload {
-            activity: Some(activity.clone()),
-            pid: args.pid,
-            socket_id: Some(args.pid.unwrap_or(0).to_string()),
-          };
+          ProcessDetectedEvent::Stopped(proc_activity) => {
+            log!(
+              "[Client Connector] Sending empty payload for activity: {}",
+              proc_activity.name
+            );
 
-          match serde_json::to_string(&payload) {
-            Ok(payload) => {
-              log!(
-                "[Client Connector] Sending payload for IPC activity: {:?}",
-                payload
-              );
-              ipc_clone.send_data(payload)
-            }
-            Err(err) => log!("[Client Connector] Error serializing IPC activity: {}", err),
-          };
-        } else {
-          log!("[Client Connector] Invalid activity command, skipping");
+            proc_clone.events.emit(RPCEvent::ActivityCleared {
+              source: ActivitySource::Process,
+              socket_id: proc_activity.id.clone(),
+              pid: proc_activity.pid.unwrap_or_default(),
+            });
+          }
         }
       }
-    });
-
-    std::thread::spawn(move || {
-      while let Ok(proc_event) = proc_event_rec.recv() {
-        let proc_activity = proc_event.activity;
+    }));
 
-        // if there are no clients, skip
-        if proc_clone.clients.lock().unwrap().is_empty() {
-          log!("[Client Connector] No clients connected, skipping");
-          continue;
-        }
+    let running = self.running.clone();
+    threads.push(std::thread::spawn(move || {
+      while let Some(ws_event) = recv_while_running(&ws_event_rec, &running) {
+        ws_clone.handle_activity_cmd(ActivitySource::Websocket, ws_event);
+      }
+    }));
 
-        if proc_activity.id == "null" {
-          // If our last socket id is empty, skip
-          if proc_clone.active_socket.is_none() {
-            continue;
-          }
+    Ok(())
+  }
 
-          // Send an empty payload
-          log!("[Client Connector] Sending empty payload");
+  /**
+   * Turn a SET_ACTIVITY command from the IPC or websocket connectors into an event
+   */
+  fn handle_activity_cmd(&self, source: ActivitySource, mut activity_cmd: ActivityCmd) {
+    activity_cmd.fix();
 
-          let payload = empty_activity(
-            proc_clone.last_pid.unwrap_or_default(),
-            proc_clone.active_socket.as_ref().unwrap().clone(),
-          );
+    let Some(args) = activity_cmd.args else {
+      log!("[Client Connector] Invalid activity command, skipping");
+      return;
+    };
 
-          proc_clone.send_data(payload);
+    let pid = args.pid.unwrap_or_default();
 
-          proc_clone.active_socket = None;
+    let Some(mut activity) = args.activity else {
+      log!("[Client Connector] Sending empty payload");
 
-          continue;
-        }
+      self.events.emit(RPCEvent::ActivityCleared {
+        source,
+        socket_id: pid.to_string(),
+        pid,
+      });
 
-        // If the active socket is different from the current socket, send an empty payload for the old socket
-        if proc_clone.active_socket != Some(proc_activity.id.clone()) {
-          if proc_clone.active_socket.is_some() {
-            // Send an empty payload
-            log!("[Client Connector] Sending empty payload");
+      return;
+    };
 
-            let payload = empty_activity(
-              proc_clone.last_pid.unwrap_or_default(),
-              proc_clone.active_socket.as_ref().unwrap().clone(),
-            );
+    activity.application_id = activity_cmd.application_id;
 
-            proc_clone.send_data(payload);
-          }
-        } else {
-          log!(
-            "[Client Connector] Already sent payload for activity: {}",
-            proc_activity.name
-          );
-          continue;
-        }
+    log!(
+      "[Client Connector] Sending payload for {:?} activity: {:?}",
+      source,
+      activity
+    );
 
-        let payload_struct = ProcessPayload {
-          activity: ProcessActivity {
-            application_id: proc_activity.id.clone(),
-            name: proc_activity.name.clone(),
-            timestamps: ProcessTimestamps {
-              start: proc_activity
-                .timestamp
-                .as_ref()
-                .cloned()
-                .unwrap_or_else(|| "0".to_string()),
-            },
-            r#type: 0,
-            metadata: HashMap::new(),
-            flags: 0,
-          },
-          pid: proc_activity.pid.unwrap_or_default(),
-          socket_id: proc_activity.id.clone(),
-        };
-
-        proc_clone.last_pid = proc_activity.pid;
-        proc_clone.active_socket = Some(proc_activity.id.clone());
-
-        log!(
-          "[Client Connector] Sending payload for activity: {}",
-          proc_activity.name
-        );
-
-        match serde_json::to_string(&payload_struct) {
-          Ok(payload) => proc_clone.send_data(payload),
-          Err(err) => log!(
-            "[Client Connector] Error serializing process activity: {}",
-            err
-          ),
-        }
-      }
+    self.events.emit(RPCEvent::ActivitySet {
+      source,
+      socket_id: pid.to_string(),
+      pid,
+      activity: Box::new(activity),
     });
+  }
+
+  /**
+   * Run the bridge websocket server, forwarding every event to its clients
+   */
+  fn start_bridge(&self) -> JoinHandle<()> {
+    let server = self.server.lock().unwrap().take();
+    let bridge_events = self.bridge_events.lock().unwrap().take();
+    let mut clone = self.clone();
 
     std::thread::spawn(move || {
-      while let Ok(mut ws_event) = ws_event_rec.recv() {
-        // if there are no clients, skip
-        if ws_clone.clients.lock().unwrap().is_empty() {
-          log!("[Client Connector] No clients connected, skipping");
-          continue;
-        }
+      let (Some(mut server), Some(bridge_events)) = (server, bridge_events) else {
+        return;
+      };
 
-        if ws_event.cmd != "SET_ACTIVITY" {
-          // Just send the event as-is, there isn't really anything to go off of here
-          // I will change this if arRPC implements things like INVITE_BROWSER event responses, to ensure compatibility
-          let payload = serde_json::to_string(&ws_event).unwrap_or("".to_string());
-          log!("[Client Connector] Sending payload for WS event");
-          ws_clone.send_data(payload);
+      // Last payload per socket id, so clients connecting later still see what is currently going on
+      let mut current: HashMap<String, String> = HashMap::new();
 
-          continue;
-        }
+      while clone.running.load(Ordering::Relaxed) {
+        let mut idle = true;
 
-        ws_event.fix();
+        while let Ok(event) = bridge_events.try_recv() {
+          idle = false;
 
-        let mut args = match ws_event.args {
-          Some(args) => args,
-          None => {
-            log!("[Client Connector] Invalid activity command, skipping");
+          let Some(payload) = bridge_payload(&event) else {
             continue;
-          }
-        };
-
-        if args.activity.is_none() {
-          let pid = args.pid.unwrap_or_default();
-          // Send empty payload
-          let payload = empty_activity(pid, pid.to_string());
-
-          log!("[Client Connector] Sending empty payload");
+          };
 
-          ws_clone.send_data(payload);
+          match &event {
+            RPCEvent::ActivitySet { socket_id, .. } => {
+              current.insert(socket_id.clone(), payload.clone());
+            }
+            RPCEvent::ActivityCleared { socket_id, .. } => {
+              current.remove(socket_id);
+            }
+            _ => {}
+          }
 
-          continue;
+          clone.send_data(payload);
         }
 
-        let activity = args.activity.as_mut();
+        if let Some(event) = server.next_event() {
+          idle = false;
 
-        if let Some(activity) = activity {
-          activity.application_id = ws_event.application_id;
+          match event {
+            Event::Connect(client_id, responder) => {
+              log!("[Client Connector] Client {} connected", client_id);
 
-          let payload = ActivityPayload {
-            activity: Some(activity.clone()),
-            pid: args.pid,
-            socket_id: Some(args.pid.unwrap_or(0).to_string()),
-          };
+              // Send initial connection data
+              responder.send(Message::Text(clone.ready.message()));
 
-          match serde_json::to_string(&payload) {
-            Ok(payload) => {
+              for payload in current.values() {
+                responder.send(Message::Text(payload.clone()));
+              }
+
+              clone.clients.lock().unwrap().insert(client_id, responder);
+            }
+            Event::Disconnect(client_id) => {
+              clone.clients.lock().unwrap().remove(&client_id);
+            }
+            Event::Message(client_id, message) => {
               log!(
-                "[Client Connector] Sending payload for WS activity: {:?}",
-                payload
+                "[Client Connector] Received message from client {}: {:?}",
+                client_id,
+                message
               );
-              ws_clone.send_data(payload)
+
+              if let Some((request_id, response)) = invite_response(&message) {
+                if !clone.pending_invites.resolve(request_id, response) {
+                  log!(
+                    "[Client Connector] Invite {} was already answered or timed out",
+                    request_id
+                  );
+                }
+                continue;
+              }
+
+              let clients = clone.clients.lock().unwrap();
+              if let Some(responder) = clients.get(&client_id) {
+                responder.send(message);
+              }
             }
-            Err(err) => log!("[Client Connector] Error serializing WS activity: {}", err),
-          };
-        } else {
-          log!("[Client Connector] Invalid activity command, skipping");
+          }
+        }
+
+        if idle {
+          std::thread::sleep(Duration::from_millis(50));
         }
       }
-    });
+
+      // Close every bridge client before the server itself shuts down
+      for (_, responder) in clone.clients.lock().unwrap().drain() {
+        responder.close();
+      }
+
+      server.shutdown();
+
+      log!("[Client Connector] Stopped");
+    })
+  }
+
+  /**
+   * Stop the bridge server and every receiver thread, then wait for them to exit
+   */
+  pub fn stop(&self) {
+    self.running.store(false, Ordering::Relaxed);
+    join_all(&self.threads, "Client Connector");
   }
 
   pub fn send_data(&mut self, data: String) {
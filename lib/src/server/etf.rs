@@ -0,0 +1,456 @@
+//! Erlang External Term Format, for websocket clients that connect with `encoding=etf`. Only the terms needed to
+//! carry JSON are supported: maps, lists, tuples (read as lists), binaries, strings, atoms, integers and floats.
+
+use std::fmt;
+
+use serde_json::{Map, Number, Value};
+
+const VERSION: u8 = 131;
+
+const NEW_FLOAT_EXT: u8 = 70;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const FLOAT_EXT: u8 = 99;
+const ATOM_EXT: u8 = 100;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const LARGE_BIG_EXT: u8 = 111;
+const SMALL_ATOM_EXT: u8 = 115;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+/// How deeply terms may nest before we give up, so a hostile payload can't blow the stack
+const MAX_DEPTH: usize = 128;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EtfError {
+  InvalidVersion(u8),
+  UnsupportedTag(u8),
+  UnexpectedEnd,
+  TrailingData,
+  TooDeep,
+  /// An integer that doesn't fit in 64 bits
+  IntegerTooLarge,
+  InvalidFloat,
+  InvalidUtf8,
+}
+
+impl fmt::Display for EtfError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      EtfError::InvalidVersion(version) => write!(f, "unsupported ETF version {version}"),
+      EtfError::UnsupportedTag(tag) => write!(f, "unsupported ETF tag {tag}"),
+      EtfError::UnexpectedEnd => write!(f, "unexpected end of ETF data"),
+      EtfError::TrailingData => write!(f, "trailing data after ETF term"),
+      EtfError::TooDeep => write!(f, "ETF terms nested too deeply"),
+      EtfError::IntegerTooLarge => write!(f, "ETF integer does not fit in 64 bits"),
+      EtfError::InvalidFloat => write!(f, "invalid ETF float"),
+      EtfError::InvalidUtf8 => write!(f, "ETF string is not valid UTF-8"),
+    }
+  }
+}
+
+/**
+ * Encode a JSON value the way erlpack does: strings and keys as binaries, null as the `nil` atom
+ */
+pub fn encode(value: &Value) -> Vec<u8> {
+  let mut buf = vec![VERSION];
+  encode_term(&mut buf, value);
+  buf
+}
+
+fn encode_term(buf: &mut Vec<u8>, value: &Value) {
+  match value {
+    Value::Null => encode_atom(buf, "nil"),
+    Value::Bool(true) => encode_atom(buf, "true"),
+    Value::Bool(false) => encode_atom(buf, "false"),
+    Value::Number(number) => encode_number(buf, number),
+    Value::String(string) => encode_binary(buf, string),
+    Value::Array(items) => {
+      if !items.is_empty() {
+        buf.push(LIST_EXT);
+        buf.extend_from_slice(&(items.len() as u32).to_be_bytes());
+
+        for item in items {
+          encode_term(buf, item);
+        }
+      }
+
+      // Proper lists end with an empty one
+      buf.push(NIL_EXT);
+    }
+    Value::Object(map) => {
+      buf.push(MAP_EXT);
+      buf.extend_from_slice(&(map.len() as u32).to_be_bytes());
+
+      for (key, value) in map {
+        encode_binary(buf, key);
+        encode_term(buf, value);
+      }
+    }
+  }
+}
+
+fn encode_atom(buf: &mut Vec<u8>, name: &str) {
+  buf.push(SMALL_ATOM_UTF8_EXT);
+  buf.push(name.len() as u8);
+  buf.extend_from_slice(name.as_bytes());
+}
+
+fn encode_binary(buf: &mut Vec<u8>, string: &str) {
+  buf.push(BINARY_EXT);
+  buf.extend_from_slice(&(string.len() as u32).to_be_bytes());
+  buf.extend_from_slice(string.as_bytes());
+}
+
+fn encode_number(buf: &mut Vec<u8>, number: &Number) {
+  if let Some(value) = number.as_u64() {
+    if value <= u8::MAX as u64 {
+      buf.push(SMALL_INTEGER_EXT);
+      buf.push(value as u8);
+    } else if value <= i32::MAX as u64 {
+      buf.push(INTEGER_EXT);
+      buf.extend_from_slice(&(value as i32).to_be_bytes());
+    } else {
+      encode_big(buf, value, false);
+    }
+  } else if let Some(value) = number.as_i64() {
+    if value >= i32::MIN as i64 {
+      buf.push(INTEGER_EXT);
+      buf.extend_from_slice(&(value as i32).to_be_bytes());
+    } else {
+      encode_big(buf, value.unsigned_abs(), true);
+    }
+  } else {
+    buf.push(NEW_FLOAT_EXT);
+    buf.extend_from_slice(&number.as_f64().unwrap_or_default().to_be_bytes());
+  }
+}
+
+fn encode_big(buf: &mut Vec<u8>, magnitude: u64, negative: bool) {
+  let digits = magnitude.to_le_bytes();
+  let len = 8 - magnitude.leading_zeros() as usize / 8;
+
+  buf.push(SMALL_BIG_EXT);
+  buf.push(len as u8);
+  buf.push(negative as u8);
+  buf.extend_from_slice(&digits[..len]);
+}
+
+/**
+ * Decode a term into JSON. Atoms other than `nil`, `true` and `false` become strings, and map keys that aren't
+ * strings are stringified.
+ */
+pub fn decode(data: &[u8]) -> Result<Value, EtfError> {
+  let mut reader = Reader { data, pos: 0 };
+
+  let version = reader.u8()?;
+  if version != VERSION {
+    return Err(EtfError::InvalidVersion(version));
+  }
+
+  let value = reader.term(0)?;
+
+  if reader.pos != data.len() {
+    return Err(EtfError::TrailingData);
+  }
+
+  Ok(value)
+}
+
+struct Reader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl Reader<'_> {
+  fn take(&mut self, len: usize) -> Result<&[u8], EtfError> {
+    let end = self.pos.checked_add(len).ok_or(EtfError::UnexpectedEnd)?;
+    let bytes = self
+      .data
+      .get(self.pos..end)
+      .ok_or(EtfError::UnexpectedEnd)?;
+    self.pos = end;
+
+    Ok(bytes)
+  }
+
+  fn u8(&mut self) -> Result<u8, EtfError> {
+    Ok(self.take(1)?[0])
+  }
+
+  fn u16(&mut self) -> Result<u16, EtfError> {
+    let bytes = self.take(2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+  }
+
+  fn u32(&mut self) -> Result<u32, EtfError> {
+    let bytes = self.take(4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+  }
+
+  fn string(&mut self, len: usize) -> Result<String, EtfError> {
+    let bytes = self.take(len)?.to_vec();
+    String::from_utf8(bytes).map_err(|_| EtfError::InvalidUtf8)
+  }
+
+  fn term(&mut self, depth: usize) -> Result<Value, EtfError> {
+    if depth > MAX_DEPTH {
+      return Err(EtfError::TooDeep);
+    }
+
+    let tag = self.u8()?;
+
+    match tag {
+      SMALL_INTEGER_EXT => Ok(Value::from(self.u8()?)),
+      INTEGER_EXT => Ok(Value::from(self.u32()? as i32)),
+      NEW_FLOAT_EXT => {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        let value = f64::from_be_bytes(bytes);
+        Number::from_f64(value)
+          .map(Value::Number)
+          .ok_or(EtfError::InvalidFloat)
+      }
+      FLOAT_EXT => {
+        // Old-style floats are printed into a 31 byte, NUL padded string
+        let text = self.string(31)?;
+        let value = text
+          .trim_end_matches('\0')
+          .trim()
+          .parse::<f64>()
+          .map_err(|_| EtfError::InvalidFloat)?;
+        Number::from_f64(value)
+          .map(Value::Number)
+          .ok_or(EtfError::InvalidFloat)
+      }
+      ATOM_EXT | ATOM_UTF8_EXT => {
+        let len = self.u16()? as usize;
+        Ok(atom(self.string(len)?))
+      }
+      SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT => {
+        let len = self.u8()? as usize;
+        Ok(atom(self.string(len)?))
+      }
+      NIL_EXT => Ok(Value::Array(vec![])),
+      STRING_EXT => {
+        let len = self.u16()? as usize;
+        Ok(Value::String(self.string(len)?))
+      }
+      BINARY_EXT => {
+        let len = self.u32()? as usize;
+        Ok(Value::String(self.string(len)?))
+      }
+      LIST_EXT => {
+        let len = self.u32()?;
+        let mut items = vec![];
+
+        for _ in 0..len {
+          items.push(self.term(depth + 1)?);
+        }
+
+        // The tail of a proper list is NIL_EXT, anything else is an improper list
+        match self.term(depth + 1)? {
+          Value::Array(tail) if tail.is_empty() => Ok(Value::Array(items)),
+          _ => Err(EtfError::UnsupportedTag(LIST_EXT)),
+        }
+      }
+      SMALL_TUPLE_EXT | LARGE_TUPLE_EXT => {
+        let len = if tag == SMALL_TUPLE_EXT {
+          self.u8()? as u32
+        } else {
+          self.u32()?
+        };
+        let mut items = vec![];
+
+        for _ in 0..len {
+          items.push(self.term(depth + 1)?);
+        }
+
+        Ok(Value::Array(items))
+      }
+      SMALL_BIG_EXT | LARGE_BIG_EXT => {
+        let len = if tag == SMALL_BIG_EXT {
+          self.u8()? as usize
+        } else {
+          self.u32()? as usize
+        };
+        let negative = self.u8()? != 0;
+        let digits = self.take(len)?;
+
+        // Leading zero digits are allowed, as long as what's left fits
+        if digits.iter().skip(8).any(|digit| *digit != 0) {
+          return Err(EtfError::IntegerTooLarge);
+        }
+
+        let magnitude = digits
+          .iter()
+          .take(8)
+          .rev()
+          .fold(0u64, |acc, digit| (acc << 8) | *digit as u64);
+
+        if !negative {
+          Ok(Value::from(magnitude))
+        } else if magnitude <= i64::MAX as u64 + 1 {
+          Ok(Value::from((magnitude as i64).wrapping_neg()))
+        } else {
+          Err(EtfError::IntegerTooLarge)
+        }
+      }
+      MAP_EXT => {
+        let len = self.u32()?;
+        let mut map = Map::new();
+
+        for _ in 0..len {
+          let key = match self.term(depth + 1)? {
+            Value::String(key) => key,
+            key => key.to_string(),
+          };
+          let value = self.term(depth + 1)?;
+
+          map.insert(key, value);
+        }
+
+        Ok(Value::Object(map))
+      }
+      _ => Err(EtfError::UnsupportedTag(tag)),
+    }
+  }
+}
+
+fn atom(name: String) -> Value {
+  match name.as_str() {
+    "nil" => Value::Null,
+    "true" => Value::Bool(true),
+    "false" => Value::Bool(false),
+    _ => Value::String(name),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  /// `{"cmd" => "SET_ACTIVITY", "nonce" => "1"}`, the way erlpack packs it
+  const COMMAND: &[u8] = &[
+    131, 116, 0, 0, 0, 2, //
+    109, 0, 0, 0, 3, b'c', b'm', b'd', //
+    109, 0, 0, 0, 12, b'S', b'E', b'T', b'_', b'A', b'C', b'T', b'I', b'V', b'I', b'T',
+    b'Y', //
+    109, 0, 0, 0, 5, b'n', b'o', b'n', b'c', b'e', //
+    109, 0, 0, 0, 1, b'1',
+  ];
+
+  #[test]
+  fn round_trips_a_command() {
+    let value = json!({ "cmd": "SET_ACTIVITY", "nonce": "1" });
+
+    assert_eq!(encode(&value), COMMAND);
+    assert_eq!(decode(COMMAND).unwrap(), value);
+  }
+
+  #[test]
+  fn round_trips_scalars() {
+    let fixtures: &[(Value, &[u8])] = &[
+      (Value::Null, &[131, 119, 3, b'n', b'i', b'l']),
+      (json!(true), &[131, 119, 4, b't', b'r', b'u', b'e']),
+      (json!(7), &[131, 97, 7]),
+      (json!(-1), &[131, 98, 255, 255, 255, 255]),
+      (json!(70000), &[131, 98, 0, 1, 17, 112]),
+      (json!(1.5), &[131, 70, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0]),
+      // A snowflake, which needs a bignum
+      (
+        json!(1045800378228281345u64),
+        &[
+          131, 110, 8, 0, 0x01, 0x10, 0x44, 0x32, 0xe7, 0x6d, 0x83, 0x0e,
+        ],
+      ),
+      (
+        json!(-3000000000i64),
+        &[131, 110, 4, 1, 0x00, 0x5e, 0xd0, 0xb2],
+      ),
+    ];
+
+    for (value, etf) in fixtures {
+      assert_eq!(&encode(value), etf, "encoding {value}");
+      assert_eq!(&decode(etf).unwrap(), value, "decoding {value}");
+    }
+  }
+
+  #[test]
+  fn round_trips_lists() {
+    let empty: &[u8] = &[131, 106];
+    let list: &[u8] = &[131, 108, 0, 0, 0, 2, 97, 1, 109, 0, 0, 0, 1, b'a', 106];
+
+    assert_eq!(encode(&json!([])), empty);
+    assert_eq!(decode(empty).unwrap(), json!([]));
+    assert_eq!(encode(&json!([1, "a"])), list);
+    assert_eq!(decode(list).unwrap(), json!([1, "a"]));
+  }
+
+  #[test]
+  fn round_trips_nested_values() {
+    let value = json!({
+      "cmd": "SET_ACTIVITY",
+      "args": {
+        "pid": 1234,
+        "activity": {
+          "details": "Testing",
+          "timestamps": { "start": 1700000000000u64 },
+          "buttons": [{ "label": "Website", "url": "https://example.com" }],
+          "instance": false,
+          "party": null,
+        },
+      },
+      "nonce": "2",
+    });
+
+    assert_eq!(decode(&encode(&value)).unwrap(), value);
+  }
+
+  #[test]
+  fn decodes_what_discord_sends() {
+    // Atom keys, an old-style string and a tuple
+    let etf: &[u8] = &[
+      131, 116, 0, 0, 0, 3, //
+      100, 0, 3, b'c', b'm', b'd', 107, 0, 2, b'h', b'i', //
+      115, 3, b'e', b'v', b't', 100, 0, 3, b'n', b'i', b'l', //
+      118, 0, 4, b'd', b'a', b't', b'a', 104, 2, 97, 1, 97, 2,
+    ];
+
+    assert_eq!(
+      decode(etf).unwrap(),
+      json!({ "cmd": "hi", "evt": null, "data": [1, 2] })
+    );
+  }
+
+  #[test]
+  fn rejects_garbage() {
+    assert_eq!(decode(b"{\"cmd\":1}"), Err(EtfError::InvalidVersion(b'{')));
+    assert_eq!(decode(&[131, 80, 0]), Err(EtfError::UnsupportedTag(80)));
+    assert_eq!(
+      decode(&[131, 109, 0, 0, 0, 5, b'a']),
+      Err(EtfError::UnexpectedEnd)
+    );
+    assert_eq!(decode(&[131, 97, 1, 97]), Err(EtfError::TrailingData));
+    assert_eq!(
+      decode(&[131, 109, 0, 0, 0, 1, 0xff]),
+      Err(EtfError::InvalidUtf8)
+    );
+    assert_eq!(
+      decode(&[131, 110, 9, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]),
+      Err(EtfError::IntegerTooLarge)
+    );
+
+    let mut deep = vec![131];
+    deep.extend(std::iter::repeat_n([108, 0, 0, 0, 1], 200).flatten());
+    assert_eq!(decode(&deep), Err(EtfError::TooDeep));
+  }
+}
@@ -0,0 +1,132 @@
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use serde_json::Value;
+use simple_websockets::Responder;
+
+use crate::{events::InviteResponse, log};
+
+use super::{
+  utils::{CommandResponse, RpcErrorCode},
+  websocket::Encoding,
+};
+
+struct PendingInvite {
+  code: String,
+  nonce: Value,
+  responder: Responder,
+  encoding: Encoding,
+  deadline: Instant,
+}
+
+/**
+ * INVITE_BROWSER requests waiting for the embedder or a bridge client to say what became of them. Clones share
+ * the same table.
+ */
+#[derive(Clone)]
+pub struct PendingInvites {
+  invites: Arc<Mutex<HashMap<u64, PendingInvite>>>,
+  next_id: Arc<AtomicU64>,
+  timeout: Duration,
+}
+
+impl PendingInvites {
+  pub fn new(timeout: Duration) -> Self {
+    Self {
+      invites: Arc::new(Mutex::new(HashMap::new())),
+      next_id: Arc::new(AtomicU64::new(0)),
+      timeout,
+    }
+  }
+
+  /**
+   * Hold on to a request until it is resolved or times out, returning its id
+   */
+  pub fn add(&self, code: String, nonce: Value, responder: Responder, encoding: Encoding) -> u64 {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+    self.invites.lock().unwrap().insert(
+      id,
+      PendingInvite {
+        code,
+        nonce,
+        responder,
+        encoding,
+        deadline: Instant::now() + self.timeout,
+      },
+    );
+
+    id
+  }
+
+  /**
+   * Answer the website that asked for this invite. Returns false if the request is unknown or already answered.
+   */
+  pub fn resolve(&self, id: u64, response: InviteResponse) -> bool {
+    let Some(invite) = self.invites.lock().unwrap().remove(&id) else {
+      return false;
+    };
+
+    log!("[Websocket] Invite {} was {:?}", invite.code, response);
+
+    let response = match response {
+      InviteResponse::Accepted => CommandResponse::new(
+        "INVITE_BROWSER".to_string(),
+        serde_json::json!({ "code": invite.code }),
+        invite.nonce,
+      ),
+      InviteResponse::Rejected => CommandResponse::error(
+        "INVITE_BROWSER".to_string(),
+        invite.nonce,
+        RpcErrorCode::UnknownError,
+        "Invite was rejected",
+      ),
+      InviteResponse::Invalid => CommandResponse::error(
+        "INVITE_BROWSER".to_string(),
+        invite.nonce,
+        RpcErrorCode::InvalidInvite,
+        "Invalid invite",
+      ),
+    };
+
+    invite.responder.send(invite.encoding.message(&response));
+
+    true
+  }
+
+  /**
+   * Give up on requests nobody answered in time
+   */
+  pub fn expire(&self) {
+    let now = Instant::now();
+    let mut invites = self.invites.lock().unwrap();
+    let expired: Vec<u64> = invites
+      .iter()
+      .filter(|(_, invite)| invite.deadline <= now)
+      .map(|(id, _)| *id)
+      .collect();
+
+    for id in expired {
+      let Some(invite) = invites.remove(&id) else {
+        continue;
+      };
+
+      log!("[Websocket] Invite {} timed out", invite.code);
+
+      invite
+        .responder
+        .send(invite.encoding.message(&CommandResponse::error(
+          "INVITE_BROWSER".to_string(),
+          invite.nonce,
+          RpcErrorCode::UnknownError,
+          "Timed out waiting for the invite to be handled",
+        )));
+    }
+  }
+}
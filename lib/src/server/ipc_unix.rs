@@ -1,19 +1,30 @@
 use interprocess::local_socket::traits::{Listener as _, Stream as _};
 use interprocess::local_socket::{
-  GenericFilePath, Listener, ListenerNonblockingMode, ListenerOptions, Stream, ToFsName,
+  GenericFilePath, Listener, ListenerNonblockingMode, ListenerOptions, RecvHalf, Stream, ToFsName,
 };
 use std::env;
-use std::io::ErrorKind;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use crate::cmd::ActivityCmd;
-use crate::log;
-
-use super::ipc_utils::{handle_stream, IpcFacilitator};
+use crate::events::EventBus;
+use crate::{log, Error};
+
+use super::ipc_utils::{handle_stream, IpcConnections, IpcFacilitator, IPC_SLOTS};
+use super::utils::{join_all, ReadyPayload};
+
+/**
+ * Get the socket path (minus the -N suffix), using the explicitly configured directory if there is one
+ */
+pub(crate) fn get_socket_path(socket_dir: Option<&Path>, socket_prefix: &str) -> String {
+  if let Some(socket_dir) = socket_dir {
+    return socket_dir.join(socket_prefix).display().to_string();
+  }
 
-fn get_socket_path() -> String {
   let xdg_runtime_dir = env::var("XDG_RUNTIME_DIR").unwrap_or_default();
   let tmpdir = env::var("TMPDIR").unwrap_or_default();
   let tmp = env::var("TMP").unwrap_or_default();
@@ -37,164 +48,413 @@ fn get_socket_path() -> String {
     format!("{tmp_dir}/")
   };
 
-  format!("{tmp_dir}discord-ipc")
+  format!("{tmp_dir}{socket_prefix}")
+}
+
+/**
+ * Connect to an IPC socket by its full path, the way a game would
+ */
+pub(crate) fn connect(socket_path: &str) -> std::io::Result<Stream> {
+  Stream::connect(socket_path.to_fs_name::<GenericFilePath>()?)
+}
+
+/// How long a stream read blocks before checking whether the connector is shutting down
+const STREAM_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/**
+ * Read from a stream that was accepted by us. Those time out after `STREAM_POLL_INTERVAL`, so this returns
+ * `WouldBlock` at least that often while the client is quiet.
+ */
+pub(crate) fn poll_read(reader: &mut RecvHalf, buf: &mut [u8]) -> std::io::Result<usize> {
+  reader.read(buf)
+}
+
+/// Where sandboxed apps look for the socket, relative to `$XDG_RUNTIME_DIR`
+#[cfg(target_os = "linux")]
+const SANDBOX_DIRS: &[&str] = &[
+  "app/com.discordapp.Discord",
+  ".flatpak/dev.vencord.Vesktop/xdg-run",
+  "snap.discord",
+];
+
+/**
+ * Where a socket should also be reachable from for sandboxed apps. Those only have their own directory mounted,
+ * so the socket has to actually be there rather than be linked to from there.
+ */
+#[cfg(target_os = "linux")]
+fn sandbox_paths(socket_path: &str) -> Vec<PathBuf> {
+  let Ok(runtime_dir) = env::var("XDG_RUNTIME_DIR") else {
+    log!("[IPC] XDG_RUNTIME_DIR is not set, not listening in sandbox locations");
+    return vec![];
+  };
+  let Some(file_name) = Path::new(socket_path).file_name() else {
+    return vec![];
+  };
+
+  SANDBOX_DIRS
+    .iter()
+    .map(|dir| Path::new(&runtime_dir).join(dir).join(file_name))
+    .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sandbox_paths(_socket_path: &str) -> Vec<PathBuf> {
+  vec![]
+}
+
+/**
+ * Symlink `link` to a socket, for when a listener can't be bound there. Anything already there is left alone,
+ * unless it is a dead socket or a dangling link.
+ */
+fn link_socket(socket_path: &str, link: &Path) -> Option<PathBuf> {
+  if std::fs::symlink_metadata(link).is_ok() {
+    // Stream::connect follows the link, so this also catches links that point nowhere
+    let in_use = link
+      .to_path_buf()
+      .to_fs_name::<GenericFilePath>()
+      .and_then(Stream::connect)
+      .is_ok();
+
+    if in_use {
+      log!("[IPC] {} is in use, not linking", link.display());
+      return None;
+    }
+
+    let _ = std::fs::remove_file(link);
+  }
+
+  match std::os::unix::fs::symlink(socket_path, link) {
+    Ok(_) => {
+      log!("[IPC] Linked {} to {}", link.display(), socket_path);
+      Some(link.to_path_buf())
+    }
+    Err(err) => {
+      log!("[IPC] Failed to link {}: {}", link.display(), err);
+      None
+    }
+  }
 }
 
 struct BoundListener {
   socket: Listener,
   path: String,
+  slot: u8,
+  /// Whether this is a copy of the slot's socket in a sandbox location, rather than the socket itself
+  sandboxed: bool,
+  /// Symlinks to `path` in sandbox locations where a listener couldn't be bound
+  links: Vec<PathBuf>,
 }
 
 impl Drop for BoundListener {
   fn drop(&mut self) {
     log!("[IPC] Cleaning up socket: {}", self.path);
     let _ = std::fs::remove_file(&self.path);
+
+    for link in &self.links {
+      // Only remove links that still point at us, something else may have replaced them since
+      if std::fs::read_link(link).is_ok_and(|target| target == Path::new(&self.path)) {
+        let _ = std::fs::remove_file(link);
+      }
+    }
   }
 }
 
 #[derive(Clone)]
 pub struct IpcConnector {
-  socket: Arc<Mutex<BoundListener>>,
-  did_handshake: bool,
-  pub client_id: String,
-  pub pid: u64,
-  pub nonce: String,
+  sockets: Arc<Mutex<Vec<BoundListener>>>,
+  socket_base: String,
+  slot_count: u8,
+  reprobe_interval: Option<Duration>,
+  sandbox_links: bool,
+  max_frame_size: usize,
+  running: Arc<AtomicBool>,
+  threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+  connections: IpcConnections,
 
   event_sender: mpsc::Sender<ActivityCmd>,
+  events: EventBus,
+  ready: ReadyPayload,
 }
 
 impl IpcFacilitator for IpcConnector {
-  fn handshake(&self) -> bool {
-    self.did_handshake
+  fn connections(&self) -> &IpcConnections {
+    &self.connections
+  }
+
+  fn slots(&self) -> Vec<u8> {
+    self
+      .sockets
+      .lock()
+      .unwrap()
+      .iter()
+      .filter(|listener| !listener.sandboxed)
+      .map(|listener| listener.slot)
+      .collect()
   }
 
-  fn set_handshake(&mut self, handshake: bool) {
-    self.did_handshake = handshake;
+  fn is_running(&self) -> bool {
+    self.running.load(Ordering::Relaxed)
   }
 
-  fn client_id(&self) -> String {
-    self.client_id.clone()
+  /**
+   * Create a new thread that will recieve messages from the sockets
+   */
+  fn start(&mut self) {
+    let connector = self.clone();
+
+    for listener in self.sockets.lock().unwrap().iter() {
+      if let Err(err) = listener
+        .socket
+        .set_nonblocking(ListenerNonblockingMode::Accept)
+      {
+        log!("[IPC] Failed to set socket to non-blocking: {}", err);
+        return;
+      }
+    }
+
+    self.running.store(true, Ordering::Relaxed);
+
+    let handle = thread::spawn(move || {
+      let mut last_probe = Instant::now();
+
+      while connector.is_running() {
+        if let Some(interval) = connector.reprobe_interval {
+          if last_probe.elapsed() >= interval {
+            connector.claim_first_slot();
+            last_probe = Instant::now();
+          }
+        }
+
+        let streams: Vec<_> = connector
+          .sockets
+          .lock()
+          .unwrap()
+          .iter()
+          .filter_map(|listener| match listener.socket.accept() {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => None,
+            result => Some(result),
+          })
+          .collect();
+
+        if streams.is_empty() {
+          thread::sleep(Duration::from_millis(50));
+          continue;
+        }
+
+        for stream in streams {
+          match stream {
+            Ok(stream) => {
+              log!("[IPC] Incoming stream...");
+
+              // Reads time out periodically so the handler can notice a shutdown
+              if let Err(err) = stream.set_recv_timeout(Some(STREAM_POLL_INTERVAL)) {
+                log!("[IPC] Failed to set stream read timeout: {}", err);
+              }
+
+              let mut clone = connector.clone();
+              let handle = thread::spawn(move || handle_stream(&mut clone, stream));
+
+              // Forget about handlers that already finished so the list doesn't grow forever
+              let mut threads = connector.threads.lock().unwrap();
+              threads.retain(|thread| !thread.is_finished());
+              threads.push(handle);
+            }
+            Err(err) => log!("[IPC] Error: {}", err),
+          }
+        }
+      }
+
+      log!("[IPC] Stopped accepting connections");
+    });
+
+    self.threads.lock().unwrap().push(handle);
   }
 
-  fn set_client_id(&mut self, client_id: String) {
-    self.client_id = client_id;
+  /**
+   * Stop accepting connections, wait for every stream handler to exit, then unbind the sockets
+   */
+  fn stop(&mut self) {
+    self.running.store(false, Ordering::Relaxed);
+    join_all(&self.threads, "IPC");
+
+    // Dropping the BoundListeners removes the socket files
+    self.sockets.lock().unwrap().clear();
   }
 
-  fn pid(&self) -> u64 {
-    self.pid
+  fn max_frame_size(&self) -> usize {
+    self.max_frame_size
   }
 
-  fn set_pid(&mut self, pid: u64) {
-    self.pid = pid;
+  fn event_sender(&mut self) -> &mut mpsc::Sender<ActivityCmd> {
+    &mut self.event_sender
   }
 
-  fn nonce(&self) -> String {
-    self.nonce.clone()
+  fn event_bus(&self) -> &EventBus {
+    &self.events
   }
 
-  fn set_nonce(&mut self, nonce: String) {
-    self.nonce = nonce;
+  fn ready(&self) -> &ReadyPayload {
+    &self.ready
   }
+}
 
-  fn recreate_socket(&mut self) {
-    // Delete the socket, then create a new one
-    let (socket, path) = Self::create_socket(None);
-    *self.socket.lock().unwrap() = BoundListener { socket, path };
+impl IpcConnector {
+  /**
+   * Create the sockets and return a new IpcConnector
+   */
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(
+    event_sender: mpsc::Sender<ActivityCmd>,
+    events: EventBus,
+    ready: ReadyPayload,
+    socket_dir: Option<&Path>,
+    socket_prefix: &str,
+    slot_count: u8,
+    reprobe_interval: Option<Duration>,
+    sandbox_links: bool,
+    max_frame_size: usize,
+  ) -> Result<Self, Error> {
+    let socket_base = get_socket_path(socket_dir, socket_prefix);
+    let slot_count = slot_count.clamp(1, IPC_SLOTS);
+    let sockets = Self::create_sockets(&socket_base, slot_count, sandbox_links)?;
+
+    Ok(Self {
+      sockets: Arc::new(Mutex::new(sockets)),
+      socket_base,
+      slot_count,
+      reprobe_interval,
+      sandbox_links,
+      max_frame_size,
+      running: Arc::new(AtomicBool::new(false)),
+      threads: Arc::new(Mutex::new(vec![])),
+      connections: IpcConnections::default(),
+      event_sender,
+      events,
+      ready,
+    })
   }
 
   /**
-   * Create a new thread that will recieve messages from the socket
+   * Bind the first `slot_count` free slots
    */
-  fn start(&mut self) {
-    let weak_socket = Arc::downgrade(&self.socket);
-    let event_sender = self.event_sender.clone();
-    let client_id = self.client_id.clone();
-    let pid = self.pid;
-    let nonce = self.nonce.clone();
-    let did_handshake = self.did_handshake;
-
-    thread::spawn(move || {
-      if let Some(socket_arc) = weak_socket.upgrade() {
-        let socket_guard = socket_arc.lock().unwrap();
-        if let Err(err) = socket_guard
-          .socket
-          .set_nonblocking(ListenerNonblockingMode::Accept)
-        {
-          log!("[IPC] Failed to set socket to non-blocking: {}", err);
-          return;
-        }
+  fn create_sockets(
+    socket_base: &str,
+    slot_count: u8,
+    sandbox_links: bool,
+  ) -> Result<Vec<BoundListener>, Error> {
+    let mut sockets = vec![];
+    let mut bound = 0;
+    let mut last_err = None;
+
+    for slot in 0..IPC_SLOTS {
+      if bound >= slot_count {
+        break;
       }
 
-      loop {
-        let socket_arc = match weak_socket.upgrade() {
-          Some(arc) => arc,
-          None => break,
-        };
-
-        let stream = {
-          let socket_guard = socket_arc.lock().unwrap();
-          socket_guard.socket.accept()
-        };
-
-        match stream {
-          Ok(mut stream) => {
-            log!("[IPC] Incoming stream...");
-
-            let mut clone = IpcConnector {
-              socket: socket_arc.clone(),
-              did_handshake,
-              client_id: client_id.clone(),
-              pid,
-              nonce: nonce.clone(),
-              event_sender: event_sender.clone(),
-            };
-            thread::spawn(move || handle_stream(&mut clone, &mut stream));
-          }
-          Err(err) if err.kind() == ErrorKind::WouldBlock => {
-            thread::sleep(Duration::from_millis(50));
-          }
-          Err(err) => {
-            log!("[IPC] Error: {}", err);
-            break;
-          }
+      match Self::create_slot(socket_base, slot, sandbox_links) {
+        Ok(listeners) => {
+          sockets.extend(listeners);
+          bound += 1;
         }
+        Err(err) => last_err = Some(err),
       }
-    });
+    }
+
+    if sockets.is_empty() {
+      return Err(Error::IpcSocketUnavailable(last_err.unwrap_or_else(|| {
+        std::io::Error::new(ErrorKind::AddrInUse, "no IPC slot available")
+      })));
+    }
+
+    Ok(sockets)
   }
 
-  fn event_sender(&mut self) -> &mut mpsc::Sender<ActivityCmd> {
-    &mut self.event_sender
+  /**
+   * Try to take over slot 0 if someone else had it, giving up our highest slot to stay within `slot_count`
+   */
+  fn claim_first_slot(&self) {
+    if self.slots().contains(&0) {
+      return;
+    }
+
+    let listeners = match Self::create_slot(&self.socket_base, 0, self.sandbox_links) {
+      Ok(listeners) => listeners,
+      Err(_) => return,
+    };
+
+    for listener in &listeners {
+      if let Err(err) = listener
+        .socket
+        .set_nonblocking(ListenerNonblockingMode::Accept)
+      {
+        log!("[IPC] Failed to set socket to non-blocking: {}", err);
+        return;
+      }
+    }
+
+    log!("[IPC] Claimed slot 0");
+
+    let mut sockets = self.sockets.lock().unwrap();
+    sockets.splice(0..0, listeners);
+
+    // Slots are kept in order, so the ones to keep are the lowest
+    let mut slots: Vec<u8> = sockets.iter().map(|listener| listener.slot).collect();
+    slots.dedup();
+    slots.truncate(self.slot_count as usize);
+    sockets.retain(|listener| slots.contains(&listener.slot));
   }
-}
 
-impl IpcConnector {
   /**
-   * Create a socket and return a new IpcConnector
+   * Create the socket for a slot, along with its copies in sandbox locations when those are wanted
    */
-  pub fn new(event_sender: mpsc::Sender<ActivityCmd>) -> Self {
-    let (socket, path) = Self::create_socket(None);
-
-    Self {
-      socket: Arc::new(Mutex::new(BoundListener { socket, path })),
-      did_handshake: false,
-      client_id: "".to_string(),
-      pid: 0,
-      nonce: "".to_string(),
-      event_sender,
+  fn create_slot(
+    socket_base: &str,
+    slot: u8,
+    sandbox_links: bool,
+  ) -> std::io::Result<Vec<BoundListener>> {
+    let socket_path = format!("{socket_base}-{slot}");
+    let mut main = BoundListener {
+      socket: Self::create_socket(&socket_path)?,
+      path: socket_path.clone(),
+      slot,
+      sandboxed: false,
+      links: vec![],
+    };
+    let mut sandboxed = vec![];
+
+    if sandbox_links {
+      for path in sandbox_paths(&socket_path) {
+        if let Some(dir) = path.parent() {
+          let _ = std::fs::create_dir_all(dir);
+        }
+
+        let sandbox_path = path.display().to_string();
+
+        match Self::create_socket(&sandbox_path) {
+          Ok(socket) => sandboxed.push(BoundListener {
+            socket,
+            path: sandbox_path,
+            slot,
+            sandboxed: true,
+            links: vec![],
+          }),
+          Err(_) => main.links.extend(link_socket(&socket_path, &path)),
+        }
+      }
     }
+
+    sandboxed.insert(0, main);
+
+    Ok(sandboxed)
   }
 
   /**
    * ACTUALLY create a socket, and return the handle
    */
-  fn create_socket(tries: Option<u8>) -> (Listener, String) {
-    let socket_path = get_socket_path();
-    let tries = tries.unwrap_or(0);
-    let socket_path = format!("{socket_path}-{tries}");
-
+  fn create_socket(socket_path: &str) -> std::io::Result<Listener> {
     log!("[IPC] Creating socket: {}", socket_path);
 
-    let name = socket_path.clone().to_fs_name::<GenericFilePath>().unwrap();
+    let name = socket_path.to_fs_name::<GenericFilePath>()?;
     let listener_options = ListenerOptions::new().name(name.clone());
 
     let socket = match listener_options.create_sync() {
@@ -214,15 +474,15 @@ impl IpcConnector {
                 "[IPC] Socket {} is stale, removing and retrying...",
                 socket_path
               );
-              let _ = std::fs::remove_file(&socket_path);
-              let listener_options = ListenerOptions::new()
-                .name(socket_path.clone().to_fs_name::<GenericFilePath>().unwrap());
+              let _ = std::fs::remove_file(socket_path);
+              let listener_options =
+                ListenerOptions::new().name(socket_path.to_fs_name::<GenericFilePath>()?);
               if let Ok(socket) = listener_options.create_sync() {
                 log!(
                   "[IPC] Created IPC socket after cleaning stale: {}",
                   socket_path
                 );
-                return (socket, socket_path);
+                return Ok(socket);
               }
             }
           }
@@ -230,16 +490,12 @@ impl IpcConnector {
 
         log!("[IPC] Failed to create IPC socket: {}", err);
 
-        if tries < 9 {
-          return Self::create_socket(Some(tries + 1));
-        } else {
-          panic!("[IPC] Failed to create socket: {}", err);
-        }
+        return Err(err);
       }
     };
 
     log!("[IPC] Created IPC socket: {}", socket_path);
 
-    (socket, socket_path)
+    Ok(socket)
   }
 }
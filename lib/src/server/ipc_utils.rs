@@ -1,38 +1,180 @@
 use std::{
-  io::{Read, Write},
-  sync::mpsc,
+  collections::{HashMap, HashSet},
+  io::{ErrorKind, Write},
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc, Arc, Mutex,
+  },
+  time::Instant,
 };
 
-use interprocess::local_socket::Stream;
+use interprocess::local_socket::{traits::Stream as _, RecvHalf, SendHalf, Stream};
 use serde_json::Value;
 
 use crate::{
   cmd::{ActivityCmd, ActivityCmdArgs},
+  events::{ActivitySource, EventBus, RPCEvent},
   log,
-  server::utils,
+  server::ipc,
+  server::utils::{CloseCode, CommandResponse, ReadyPayload, RpcErrorCode, SUBSCRIBABLE_EVENTS},
 };
 
 pub trait IpcFacilitator {
-  fn handshake(&self) -> bool;
-  fn set_handshake(&mut self, handshake: bool);
+  fn connections(&self) -> &IpcConnections;
 
-  fn client_id(&self) -> String;
-  fn set_client_id(&mut self, client_id: String);
+  /// Which `discord-ipc-N` slots we are currently listening on
+  fn slots(&self) -> Vec<u8>;
 
-  fn pid(&self) -> u64;
-  fn set_pid(&mut self, pid: u64);
-
-  fn nonce(&self) -> String;
-  fn set_nonce(&mut self, nonce: String);
-
-  fn recreate_socket(&mut self);
+  fn is_running(&self) -> bool;
 
   fn start(&mut self);
+  fn stop(&mut self);
+
+  /// Largest frame a client may send before being disconnected
+  fn max_frame_size(&self) -> usize;
 
   fn event_sender(&mut self) -> &mut mpsc::Sender<ActivityCmd>;
+  fn event_bus(&self) -> &EventBus;
+  fn ready(&self) -> &ReadyPayload;
 }
 
-#[derive(Debug)]
+/**
+ * The sending half of an IPC stream, shared between the thread handling the session and anyone dispatching events
+ * to it. The reading half belongs to the session's thread alone, so writers never wait on a read.
+ */
+#[derive(Clone)]
+pub struct SharedSender(Arc<Mutex<SendHalf>>);
+
+impl SharedSender {
+  pub fn new(sender: SendHalf) -> Self {
+    Self(Arc::new(Mutex::new(sender)))
+  }
+
+  /**
+   * Write a whole frame, without anyone else's writes ending up in the middle of it
+   */
+  pub fn send(&self, data: &[u8]) -> std::io::Result<()> {
+    self.0.lock().unwrap().write_all(data)
+  }
+}
+
+/// State of a single connected IPC client
+#[derive(Clone)]
+pub struct IpcSession {
+  pub id: u64,
+  pub sender: SharedSender,
+  pub did_handshake: bool,
+  pub client_id: String,
+  /// The pid the client last set an activity for, if it has set one at all
+  pub pid: Option<u64>,
+  pub nonce: String,
+  /// Events the client has SUBSCRIBEd to
+  pub subscriptions: HashSet<String>,
+  pub last_activity: Instant,
+}
+
+/**
+ * Every open IPC connection, keyed by session id. Clones share the same table.
+ */
+#[derive(Clone, Default)]
+pub struct IpcConnections {
+  sessions: Arc<Mutex<HashMap<u64, IpcSession>>>,
+  next_id: Arc<AtomicU64>,
+}
+
+impl IpcConnections {
+  /**
+   * Start tracking a new connection, returning its session id
+   */
+  pub fn open(&self, sender: SharedSender) -> u64 {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+
+    self.sessions.lock().unwrap().insert(
+      id,
+      IpcSession {
+        id,
+        sender,
+        did_handshake: false,
+        client_id: "".to_string(),
+        pid: None,
+        nonce: "".to_string(),
+        subscriptions: HashSet::new(),
+        last_activity: Instant::now(),
+      },
+    );
+
+    id
+  }
+
+  /**
+   * Mark a session as active, and return a copy of it
+   */
+  pub fn touch(&self, id: u64) -> Option<IpcSession> {
+    let mut sessions = self.sessions.lock().unwrap();
+    let session = sessions.get_mut(&id)?;
+    session.last_activity = Instant::now();
+
+    Some(session.clone())
+  }
+
+  /**
+   * Modify a session, marking it as active
+   */
+  pub fn update(&self, id: u64, update: impl FnOnce(&mut IpcSession)) {
+    if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+      session.last_activity = Instant::now();
+      update(session);
+    }
+  }
+
+  pub fn close(&self, id: u64) -> Option<IpcSession> {
+    self.sessions.lock().unwrap().remove(&id)
+  }
+
+  pub fn count(&self) -> usize {
+    self.sessions.lock().unwrap().len()
+  }
+
+  /**
+   * Send a DISPATCH frame to every session with the given socket id that subscribed to `evt`. Returns whether
+   * any session received it.
+   */
+  pub fn dispatch(&self, socket_id: &str, evt: &str, data: &Value) -> bool {
+    // Don't hold the table while writing
+    let senders: Vec<SharedSender> = self
+      .sessions
+      .lock()
+      .unwrap()
+      .values()
+      .filter(|session| {
+        session.pid.is_some_and(|pid| pid.to_string() == socket_id)
+          && session.subscriptions.contains(evt)
+      })
+      .map(|session| session.sender.clone())
+      .collect();
+
+    let response = CommandResponse::dispatch(evt, data.clone());
+    let frame = encode(
+      PacketType::Frame,
+      &serde_json::to_string(&response).unwrap_or_default(),
+    );
+    let mut sent = false;
+
+    for sender in senders {
+      match sender.send(&frame) {
+        Ok(_) => sent = true,
+        Err(err) => log!("[IPC] Error dispatching {}: {}", evt, err),
+      }
+    }
+
+    sent
+  }
+}
+
+/// Clients only ever look for `discord-ipc-0` through `discord-ipc-9`
+pub const IPC_SLOTS: u8 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PacketType {
   Handshake,
   Frame,
@@ -42,14 +184,116 @@ pub enum PacketType {
 }
 
 impl PacketType {
-  pub fn from_u32(value: u32) -> Self {
+  pub fn from_u32(value: u32) -> Option<Self> {
     match value {
-      0 => PacketType::Handshake,
-      1 => PacketType::Frame,
-      2 => PacketType::Close,
-      3 => PacketType::Ping,
-      4 => PacketType::Pong,
-      _ => PacketType::Frame,
+      0 => Some(PacketType::Handshake),
+      1 => Some(PacketType::Frame),
+      2 => Some(PacketType::Close),
+      3 => Some(PacketType::Ping),
+      4 => Some(PacketType::Pong),
+      _ => None,
+    }
+  }
+}
+
+/// Largest frame accepted by default. Activities are a few KiB at most.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Size of the opcode and length prefix in front of every frame
+const HEADER_SIZE: usize = 8;
+
+#[derive(Debug)]
+pub enum FrameError {
+  /// The length prefix is over the configured maximum. The stream can't be trusted after this.
+  TooLarge {
+    size: usize,
+    max: usize,
+  },
+  /// Not one of the known packet types. The stream can't be trusted after this.
+  InvalidOpcode(u32),
+  /// The frame was consumed, but its payload isn't valid UTF-8
+  InvalidUtf8(PacketType),
+  Io(std::io::Error),
+}
+
+impl std::fmt::Display for FrameError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      FrameError::TooLarge { size, max } => {
+        write!(f, "frame of {size} bytes is over the {max} byte limit")
+      }
+      FrameError::InvalidOpcode(opcode) => write!(f, "invalid opcode {opcode}"),
+      FrameError::InvalidUtf8(r_type) => write!(f, "{r_type:?} payload is not valid UTF-8"),
+      FrameError::Io(err) => write!(f, "{err}"),
+    }
+  }
+}
+
+/**
+ * Turns the bytes read from a stream into packets. Anything after the last complete frame stays buffered until
+ * more data arrives, so one of these should live as long as the connection.
+ */
+pub struct FrameCodec {
+  buffer: Vec<u8>,
+  max_frame_size: usize,
+}
+
+impl FrameCodec {
+  pub fn new(max_frame_size: usize) -> Self {
+    Self {
+      buffer: Vec::new(),
+      max_frame_size,
+    }
+  }
+
+  /**
+   * Buffer data read from the stream
+   */
+  pub fn extend(&mut self, data: &[u8]) {
+    self.buffer.extend_from_slice(data);
+  }
+
+  /**
+   * Take the next complete packet out of the buffer, if there is one
+   */
+  pub fn decode(&mut self) -> Result<Option<(PacketType, String)>, FrameError> {
+    if self.buffer.len() < HEADER_SIZE {
+      return Ok(None);
+    }
+
+    let opcode = u32::from_le_bytes([
+      self.buffer[0],
+      self.buffer[1],
+      self.buffer[2],
+      self.buffer[3],
+    ]);
+    let size = u32::from_le_bytes([
+      self.buffer[4],
+      self.buffer[5],
+      self.buffer[6],
+      self.buffer[7],
+    ]) as usize;
+
+    let r_type = PacketType::from_u32(opcode).ok_or(FrameError::InvalidOpcode(opcode))?;
+
+    // Checked before waiting for the payload, so a bogus length can't make us buffer gigabytes
+    if size > self.max_frame_size {
+      return Err(FrameError::TooLarge {
+        size,
+        max: self.max_frame_size,
+      });
+    }
+
+    if self.buffer.len() < HEADER_SIZE + size {
+      return Ok(None);
+    }
+
+    let payload = self.buffer[HEADER_SIZE..HEADER_SIZE + size].to_vec();
+    self.buffer.drain(..HEADER_SIZE + size);
+
+    match String::from_utf8(payload) {
+      Ok(message) => Ok(Some((r_type, message))),
+      Err(_) => Err(FrameError::InvalidUtf8(r_type)),
     }
   }
 }
@@ -60,6 +304,62 @@ pub struct Handshake {
   pub client_id: String,
 }
 
+/**
+ * Check a handshake payload the way Discord does: version 1 and a snowflake client id
+ */
+fn validate_handshake(message: &str) -> Result<Handshake, (CloseCode, String)> {
+  let value = serde_json::from_str::<Value>(message).map_err(|err| {
+    (
+      CloseCode::Unsupported,
+      format!("Invalid handshake payload: {err}"),
+    )
+  })?;
+
+  let v = match value["v"].as_u64() {
+    Some(1) => 1,
+    _ => {
+      return Err((
+        CloseCode::InvalidVersion,
+        format!("Invalid Version: {}", value["v"]),
+      ))
+    }
+  };
+
+  let client_id = value["client_id"].as_str().unwrap_or_default();
+
+  // Snowflakes are u64s, sent as strings
+  if client_id.is_empty()
+    || !client_id.bytes().all(|b| b.is_ascii_digit())
+    || client_id.parse::<u64>().is_err()
+  {
+    return Err((
+      CloseCode::InvalidClientId,
+      format!("Invalid Client ID: {client_id}"),
+    ));
+  }
+
+  Ok(Handshake {
+    v,
+    client_id: client_id.to_string(),
+  })
+}
+
+/**
+ * Tell the client why the connection is going away. The stream itself is closed once the session ends.
+ */
+fn send_close(sender: &SharedSender, code: CloseCode, message: &str) {
+  let payload = serde_json::json!({
+    "code": code as u32,
+    "message": message,
+  });
+  let resp = encode(PacketType::Close, &payload.to_string());
+
+  match sender.send(&resp) {
+    Ok(_) => (),
+    Err(err) => log!("[IPC] Error sending close: {}", err),
+  }
+}
+
 pub fn encode(r_type: PacketType, data: &str) -> Vec<u8> {
   let mut buffer: Vec<u8> = Vec::with_capacity(8 + data.len());
 
@@ -93,162 +393,335 @@ pub fn send_empty(
   event_sender.send(activity)
 }
 
-pub fn handle_stream(ipc: &mut dyn IpcFacilitator, stream: &mut Stream) {
+/**
+ * Read until the codec has a whole packet, retrying reads that time out for as long as the connector is running
+ */
+fn read_packet(
+  ipc: &dyn IpcFacilitator,
+  reader: &mut RecvHalf,
+  codec: &mut FrameCodec,
+) -> Result<(PacketType, String), FrameError> {
+  let mut chunk = [0; 4096];
+
   loop {
-    let current_pid = ipc.pid();
-    // Read into buffer
-    let mut buffer = std::io::BufReader::new(&mut *stream);
+    if let Some(packet) = codec.decode()? {
+      return Ok(packet);
+    }
 
-    // Read the packet type and size
-    let mut packet_type = [0; 4];
-    let mut data_size = [0; 4];
+    if !ipc.is_running() {
+      return Err(FrameError::Io(std::io::Error::new(
+        ErrorKind::Interrupted,
+        "IPC connector is shutting down",
+      )));
+    }
 
-    match buffer.by_ref().take(4).read_exact(&mut packet_type) {
-      Ok(_) => (),
-      Err(err) => {
-        log!(
-          "[IPC] Error reading packet type: {}, socket likely closed",
-          err
-        );
+    match ipc::poll_read(reader, &mut chunk) {
+      Ok(0) => return Err(FrameError::Io(ErrorKind::UnexpectedEof.into())),
+      Ok(read) => codec.extend(&chunk[..read]),
+      Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+      Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+      Err(err) => return Err(FrameError::Io(err)),
+    }
+  }
+}
 
-        // Send empty activity
-        send_empty(ipc.event_sender(), current_pid)
-          .unwrap_or_else(|e| log!("[IPC] Error sending empty activity: {}", e));
-        break;
-      }
+/**
+ * Handle a command from a session that completed its handshake, and build the response to send back
+ */
+fn dispatch_command(
+  ipc: &mut dyn IpcFacilitator,
+  session: &IpcSession,
+  message: &str,
+) -> CommandResponse {
+  let activity_cmd = match serde_json::from_str::<ActivityCmd>(message) {
+    Ok(cmd) => cmd,
+    Err(err) => {
+      log!("[IPC] Error parsing activity command: {}", err);
+      return CommandResponse::invalid_payload(message, &format!("Invalid payload: {err}"));
     }
+  };
 
-    match buffer.by_ref().take(4).read_exact(&mut data_size) {
-      Ok(_) => (),
-      Err(err) => {
-        log!("[IPC] Error reading data size: {}", err);
+  match activity_cmd.cmd.as_str() {
+    "SET_ACTIVITY" => handle_set_activity(ipc, session, activity_cmd),
+    "SUBSCRIBE" | "UNSUBSCRIBE" => handle_subscription(ipc, session, activity_cmd),
+    _ => {
+      log!("[IPC] Unsupported command: {}", activity_cmd.cmd);
+
+      CommandResponse::error(
+        activity_cmd.cmd,
+        activity_cmd.nonce,
+        RpcErrorCode::InvalidCommand,
+        "Invalid command",
+      )
+    }
+  }
+}
 
-        // Send empty activity
-        send_empty(ipc.event_sender(), current_pid)
-          .unwrap_or_else(|e| log!("[IPC] Error sending empty activity: {}", e));
-        break;
-      }
+fn handle_set_activity(
+  ipc: &mut dyn IpcFacilitator,
+  session: &IpcSession,
+  mut activity_cmd: ActivityCmd,
+) -> CommandResponse {
+  let Some(args) = activity_cmd.args.as_ref() else {
+    log!("[IPC] Invalid activity command, skipping");
+
+    // Clear whatever activity the session had set
+    if let Some(pid) = session.pid {
+      send_empty(ipc.event_sender(), pid)
+        .unwrap_or_else(|e| log!("[IPC] Error sending empty activity: {}", e));
     }
 
-    // Convert the rest of the buffer to a string
-    let mut message = String::new();
+    return CommandResponse::error(
+      activity_cmd.cmd,
+      activity_cmd.nonce,
+      RpcErrorCode::InvalidPayload,
+      "Missing args",
+    );
+  };
 
-    match buffer
-      .by_ref()
-      .take(u32::from_le_bytes(data_size) as u64)
-      .read_to_string(&mut message)
-    {
-      Ok(_) => (),
-      Err(err) => {
-        log!("[IPC] Error reading data: {}", err);
+  let pid = args.pid.unwrap_or_default();
+
+  // Respond with the activity as it was set, which is null when clearing it
+  let data = args.activity.clone().map(|mut activity| {
+    activity.application_id = Some(session.client_id.clone());
+    activity
+  });
+  let response = CommandResponse::new(
+    activity_cmd.cmd.clone(),
+    serde_json::to_value(data).unwrap_or_default(),
+    activity_cmd.nonce.clone(),
+  );
+
+  activity_cmd.application_id = Some(session.client_id.clone());
+
+  ipc.connections().update(session.id, |session| {
+    session.pid = Some(pid);
+    session.nonce = activity_cmd.nonce.to_string();
+  });
+
+  if let Err(err) = ipc.event_sender().send(activity_cmd) {
+    log!("[IPC] Error sending activity command: {}", err);
+
+    return CommandResponse::error(
+      response.cmd,
+      response.nonce,
+      RpcErrorCode::UnknownError,
+      "Could not set activity",
+    );
+  }
+
+  response
+}
+
+fn handle_subscription(
+  ipc: &mut dyn IpcFacilitator,
+  session: &IpcSession,
+  activity_cmd: ActivityCmd,
+) -> CommandResponse {
+  let evt = activity_cmd.evt.unwrap_or_default();
+
+  if !SUBSCRIBABLE_EVENTS.contains(&evt.as_str()) {
+    log!("[IPC] Cannot {} to event: {}", activity_cmd.cmd, evt);
+
+    return CommandResponse::error(
+      activity_cmd.cmd,
+      activity_cmd.nonce,
+      RpcErrorCode::InvalidEvent,
+      &format!("Invalid event: {evt}"),
+    );
+  }
+
+  let subscribe = activity_cmd.cmd == "SUBSCRIBE";
+
+  ipc.connections().update(session.id, |session| {
+    if subscribe {
+      session.subscriptions.insert(evt.clone());
+    } else {
+      session.subscriptions.remove(&evt);
+    }
+  });
+
+  CommandResponse::new(
+    activity_cmd.cmd,
+    serde_json::json!({ "evt": evt }),
+    activity_cmd.nonce,
+  )
+}
+
+fn send_response(sender: &SharedSender, response: &CommandResponse) {
+  let resp = encode(
+    PacketType::Frame,
+    &serde_json::to_string(response).unwrap_or_default(),
+  );
+
+  match sender.send(&resp) {
+    Ok(_) => (),
+    Err(err) => log!("[IPC] Error sending command response: {}", err),
+  }
+}
+
+/**
+ * Clear whatever activity a session set, and forget about it
+ */
+fn end_session(ipc: &mut dyn IpcFacilitator, session_id: u64) {
+  let Some(session) = ipc.connections().close(session_id) else {
+    return;
+  };
+
+  log!(
+    "[IPC] Session {} ended ({} still open)",
+    session.id,
+    ipc.connections().count()
+  );
+
+  // Only sessions that set an activity have one to clear, anything else for their pid belongs to someone else
+  let Some(pid) = session.pid else {
+    return;
+  };
+
+  let activity_cmd = ActivityCmd {
+    application_id: Some(session.client_id),
+    cmd: "SET_ACTIVITY".to_string(),
+    data: None,
+    evt: None,
+    args: Some(ActivityCmdArgs {
+      pid: Some(pid),
+      activity: None,
+      code: None,
+    }),
+    nonce: Value::String(session.nonce),
+  };
+
+  match ipc.event_sender().send(activity_cmd) {
+    Ok(_) => (),
+    Err(err) => log!("[IPC] Error sending activity command: {}", err),
+  }
+}
+
+pub fn handle_stream(ipc: &mut dyn IpcFacilitator, stream: Stream) {
+  let (mut reader, sender) = stream.split();
+  let sender = SharedSender::new(sender);
+  let session_id = ipc.connections().open(sender.clone());
+
+  log!("[IPC] Session {} opened", session_id);
+
+  read_packets(ipc, &mut reader, &sender, session_id);
+
+  // Let SDKs know they should reconnect rather than wait on a dead socket
+  if !ipc.is_running() {
+    send_close(&sender, CloseCode::Normal, "Server shutting down");
+  }
+
+  end_session(ipc, session_id);
+}
+
+fn read_packets(
+  ipc: &mut dyn IpcFacilitator,
+  reader: &mut RecvHalf,
+  sender: &SharedSender,
+  session_id: u64,
+) {
+  let mut codec = FrameCodec::new(ipc.max_frame_size());
+
+  loop {
+    let (r_type, message) = match read_packet(ipc, reader, &mut codec) {
+      Ok(packet) => packet,
+      Err(FrameError::InvalidUtf8(r_type)) => {
+        log!("[IPC] Recieved {:?} that is not valid UTF-8", r_type);
+
+        // The frame itself was well-formed, so the connection can carry on
+        send_response(
+          sender,
+          &CommandResponse::error(
+            "".to_string(),
+            Value::Null,
+            RpcErrorCode::InvalidPayload,
+            "Payload is not valid UTF-8",
+          ),
+        );
+        continue;
+      }
+      Err(FrameError::Io(err)) => {
+        log!("[IPC] Error reading packet: {}, socket likely closed", err);
         break;
       }
-    }
+      Err(err) => {
+        log!("[IPC] Error reading packet: {}", err);
 
-    let r_type = PacketType::from_u32(u32::from_le_bytes(packet_type));
+        let code = match err {
+          FrameError::TooLarge { .. } => CloseCode::TooLarge,
+          _ => CloseCode::Unsupported,
+        };
+        send_close(sender, code, &err.to_string());
+        break;
+      }
+    };
 
     log!("[IPC] Recieved message: {}", message);
 
+    let connections = ipc.connections().clone();
+
+    let Some(session) = connections.touch(session_id) else {
+      break;
+    };
+
+    // Nothing but a handshake is accepted until the client has sent one
+    if !session.did_handshake && !matches!(r_type, PacketType::Handshake | PacketType::Close) {
+      log!("[IPC] Did not handshake yet, closing");
+      send_close(
+        sender,
+        CloseCode::Unsupported,
+        "First packet must be a handshake",
+      );
+      break;
+    }
+
     match r_type {
       PacketType::Handshake => {
         log!("[IPC] Recieved handshake");
-        let Ok(data) = serde_json::from_str::<Handshake>(&message) else {
-          log!("[IPC] Error parsing handshake");
-          continue;
-        };
-
-        if data.v != 1 {
-          log!("[IPC] Invalid version: {}", data.v);
-          continue;
-        }
-
-        ipc.set_handshake(true);
-        ipc.set_client_id(data.client_id);
 
-        // Send CONNECTION_RESPONSE
-        let resp = encode(PacketType::Frame, utils::CONNECTION_REPONSE);
-
-        match stream.write_all(&resp) {
-          Ok(_) => (),
-          Err(err) => log!("[IPC] Error sending connection response: {}", err),
-        }
-      }
-      PacketType::Frame => {
-        if !ipc.handshake() {
-          log!("[IPC] Did not handshake yet, ignoring frame");
+        if session.did_handshake {
+          log!("[IPC] Already did handshake, ignoring");
           continue;
         }
 
-        let mut activity_cmd = match serde_json::from_str::<ActivityCmd>(&message) {
-          Ok(cmd) => cmd,
-          Err(err) => {
-            log!("[IPC] Error parsing activity command: {}", err);
-            continue;
+        let data = match validate_handshake(&message) {
+          Ok(data) => data,
+          Err((code, reason)) => {
+            log!("[IPC] Rejecting handshake: {}", reason);
+            send_close(sender, code, &reason);
+            break;
           }
         };
 
-        let args = match activity_cmd.args {
-          Some(ref args) => args,
-          None => {
-            log!("[IPC] Invalid activity command, skipping");
-
-            // Send empty activity
-            send_empty(ipc.event_sender(), current_pid)
-              .unwrap_or_else(|e| log!("[IPC] Error sending empty activity: {}", e));
-            continue;
-          }
-        };
+        connections.update(session_id, |session| {
+          session.did_handshake = true;
+          session.client_id = data.client_id.clone();
+        });
 
-        activity_cmd.application_id = Some(ipc.client_id());
+        ipc.event_bus().emit(RPCEvent::ClientConnected {
+          source: ActivitySource::Ipc,
+          client_id: data.client_id,
+        });
 
-        ipc.set_pid(args.pid.unwrap_or_default());
-        ipc.set_nonce(activity_cmd.nonce.to_string());
+        // Send CONNECTION_RESPONSE
+        let resp = encode(PacketType::Frame, &ipc.ready().message());
 
-        match ipc.event_sender().send(activity_cmd) {
+        match sender.send(&resp) {
           Ok(_) => (),
-          Err(err) => log!("[IPC] Error sending activity command: {}", err),
+          Err(err) => log!("[IPC] Error sending connection response: {}", err),
         }
-
+      }
+      PacketType::Frame => {
         // "IPC will echo back every command you send as a response.
         //  Use this as a lock-step feature to avoid flooding messages.
         //  Can be used to validate messages such as the Presence or Subscribes."
-        // XXX: arRPC does some editing of the message, setting data.name: "", data.type: 0, evt: null etc... why?
-        let resp = encode(PacketType::Frame, &message);
-
-        match stream.write_all(&resp) {
-          Ok(_) => (),
-          Err(err) => log!("[IPC] Error sending connection response: {}", err),
-        }
+        send_response(sender, &dispatch_command(ipc, &session, &message));
       }
       PacketType::Close => {
         log!("[IPC] Recieved close");
 
-        // Send message with an empty activity
-        let activity_cmd = ActivityCmd {
-          application_id: Some(ipc.client_id()),
-          cmd: "SET_ACTIVITY".to_string(),
-          data: None,
-          evt: None,
-          args: Some(ActivityCmdArgs {
-            pid: Some(ipc.pid()),
-            activity: None,
-            code: None,
-          }),
-          nonce: Value::String(ipc.nonce()),
-        };
-
-        match ipc.event_sender().send(activity_cmd) {
-          Ok(_) => (),
-          Err(err) => log!("[IPC] Error sending activity command: {}", err),
-        }
-
-        // reset values
-        ipc.set_handshake(false);
-        ipc.set_client_id("".to_string());
-        ipc.set_pid(0);
-
-        ipc.recreate_socket();
-
+        // Only this session ends, the listener keeps serving everyone else
         break;
       }
       PacketType::Ping => {
@@ -257,7 +730,7 @@ pub fn handle_stream(ipc: &mut dyn IpcFacilitator, stream: &mut Stream) {
         // Send a pong
         let resp = encode(PacketType::Pong, &message);
 
-        match stream.write_all(&resp) {
+        match sender.send(&resp) {
           Ok(_) => (),
           Err(err) => log!("[IPC] Error sending pong: {}", err),
         };
@@ -268,3 +741,99 @@ pub fn handle_stream(ipc: &mut dyn IpcFacilitator, stream: &mut Stream) {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn decode_all(codec: &mut FrameCodec) -> Vec<(PacketType, String)> {
+    let mut packets = vec![];
+
+    while let Some(packet) = codec.decode().unwrap() {
+      packets.push(packet);
+    }
+
+    packets
+  }
+
+  #[test]
+  fn decodes_whole_frames() {
+    let mut codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+    codec.extend(&encode(PacketType::Handshake, r#"{"v":1}"#));
+    codec.extend(&encode(PacketType::Ping, ""));
+
+    assert_eq!(
+      decode_all(&mut codec),
+      vec![
+        (PacketType::Handshake, r#"{"v":1}"#.to_string()),
+        (PacketType::Ping, "".to_string()),
+      ]
+    );
+  }
+
+  #[test]
+  fn waits_for_partial_frames() {
+    let frame = encode(PacketType::Frame, r#"{"cmd":"SET_ACTIVITY"}"#);
+    let mut codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+
+    // Split inside the header, then inside the payload
+    codec.extend(&frame[..3]);
+    assert!(codec.decode().unwrap().is_none());
+
+    codec.extend(&frame[3..12]);
+    assert!(codec.decode().unwrap().is_none());
+
+    // The rest of the frame arrives along with the start of the next one
+    codec.extend(&frame[12..]);
+    codec.extend(&frame[..5]);
+    assert_eq!(
+      codec.decode().unwrap(),
+      Some((PacketType::Frame, r#"{"cmd":"SET_ACTIVITY"}"#.to_string()))
+    );
+    assert!(codec.decode().unwrap().is_none());
+
+    codec.extend(&frame[5..]);
+    assert_eq!(decode_all(&mut codec).len(), 1);
+  }
+
+  #[test]
+  fn rejects_oversized_frames_before_their_payload() {
+    let mut codec = FrameCodec::new(16);
+    codec.extend(&encode(PacketType::Frame, "0123456789abcdef"));
+    assert_eq!(decode_all(&mut codec).len(), 1);
+
+    // Only the header is needed to tell
+    codec.extend(&encode(PacketType::Frame, "0123456789abcdefg")[..HEADER_SIZE]);
+    assert!(matches!(
+      codec.decode(),
+      Err(FrameError::TooLarge { size: 17, max: 16 })
+    ));
+  }
+
+  #[test]
+  fn rejects_unknown_opcodes() {
+    let mut codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+    codec.extend(b"GET / HTTP/1.1\r\n");
+
+    assert!(matches!(codec.decode(), Err(FrameError::InvalidOpcode(_))));
+  }
+
+  #[test]
+  fn skips_frames_with_invalid_utf8() {
+    let mut frame = encode(PacketType::Frame, "ab");
+    frame[HEADER_SIZE] = 0xff;
+
+    let mut codec = FrameCodec::new(DEFAULT_MAX_FRAME_SIZE);
+    codec.extend(&frame);
+    codec.extend(&encode(PacketType::Frame, "ok"));
+
+    assert!(matches!(
+      codec.decode(),
+      Err(FrameError::InvalidUtf8(PacketType::Frame))
+    ));
+    assert_eq!(
+      codec.decode().unwrap(),
+      Some((PacketType::Frame, "ok".to_string()))
+    );
+  }
+}
@@ -1,142 +1,324 @@
-use interprocess::local_socket::traits::ListenerExt;
-use interprocess::local_socket::{Listener, ListenerOptions, ToFsName};
+use interprocess::local_socket::traits::{Listener as _, Stream as _};
+use interprocess::local_socket::{
+  Listener, ListenerNonblockingMode, ListenerOptions, RecvHalf, Stream, ToFsName,
+};
 use interprocess::os::windows::local_socket::{ListenerOptionsExt, NamedPipe};
 use interprocess::os::windows::security_descriptor::SecurityDescriptor;
+use std::io::{ErrorKind, Read};
+use std::os::windows::io::{AsHandle, AsRawHandle};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use crate::cmd::ActivityCmd;
-use crate::log;
+use crate::events::EventBus;
+use crate::{log, Error};
 
-use super::ipc_utils::{handle_stream, IpcFacilitator};
+use winapi::um::namedpipeapi::PeekNamedPipe;
 
-#[derive(Clone)]
-pub struct IpcConnector {
-  socket: Arc<Mutex<Listener>>,
-  did_handshake: bool,
-  pub client_id: String,
-  pub pid: u64,
-  pub nonce: String,
+use super::ipc_utils::{handle_stream, IpcConnections, IpcFacilitator, IPC_SLOTS};
+use super::utils::{join_all, ReadyPayload};
 
-  event_sender: mpsc::Sender<ActivityCmd>,
+/**
+ * Get the pipe path (minus the -N suffix). Named pipes always live under \\.\pipe\, so only the prefix is
+ * configurable.
+ */
+pub(crate) fn get_socket_path(_socket_dir: Option<&Path>, socket_prefix: &str) -> String {
+  format!(r"\\.\pipe\{socket_prefix}")
 }
 
-impl IpcFacilitator for IpcConnector {
-  fn handshake(&self) -> bool {
-    self.did_handshake
-  }
+/**
+ * Connect to a pipe by its full path, the way a game would
+ */
+pub(crate) fn connect(pipe_path: &str) -> std::io::Result<Stream> {
+  Stream::connect(pipe_path.to_fs_name::<NamedPipe>()?)
+}
 
-  fn set_handshake(&mut self, handshake: bool) {
-    self.did_handshake = handshake;
-  }
+/// How often an idle pipe is checked for data, and for the connector shutting down
+const PIPE_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
-  fn client_id(&self) -> String {
-    self.client_id.clone()
-  }
+/**
+ * Read from a pipe without blocking for good. Named pipes don't support read timeouts, and a nonblocking read with
+ * nothing to read looks just like EOF, so peek at how much is waiting first and never read more than that.
+ */
+pub(crate) fn poll_read(reader: &mut RecvHalf, buf: &mut [u8]) -> std::io::Result<usize> {
+  // Named pipes are the only kind of local socket on Windows
+  let RecvHalf::NamedPipe(pipe) = &*reader;
+  let mut available = 0;
 
-  fn set_client_id(&mut self, client_id: String) {
-    self.client_id = client_id;
-  }
+  let peeked = unsafe {
+    PeekNamedPipe(
+      pipe.as_handle().as_raw_handle() as _,
+      ptr::null_mut(),
+      0,
+      ptr::null_mut(),
+      &mut available,
+      ptr::null_mut(),
+    )
+  };
 
-  fn pid(&self) -> u64 {
-    self.pid
+  // Fails with a broken pipe once the client is gone
+  if peeked == 0 {
+    return Err(std::io::Error::last_os_error());
   }
 
-  fn set_pid(&mut self, pid: u64) {
-    self.pid = pid;
+  if available == 0 {
+    thread::sleep(PIPE_POLL_INTERVAL);
+    return Err(ErrorKind::WouldBlock.into());
   }
 
-  fn nonce(&self) -> String {
-    self.nonce.clone()
+  let len = buf.len().min(available as usize);
+  reader.read(&mut buf[..len])
+}
+
+struct BoundListener {
+  socket: Listener,
+  slot: u8,
+}
+
+#[derive(Clone)]
+pub struct IpcConnector {
+  sockets: Arc<Mutex<Vec<BoundListener>>>,
+  pipe_base: String,
+  slot_count: u8,
+  reprobe_interval: Option<Duration>,
+  max_frame_size: usize,
+  running: Arc<AtomicBool>,
+  threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+  connections: IpcConnections,
+
+  event_sender: mpsc::Sender<ActivityCmd>,
+  events: EventBus,
+  ready: ReadyPayload,
+}
+
+impl IpcFacilitator for IpcConnector {
+  fn connections(&self) -> &IpcConnections {
+    &self.connections
   }
 
-  fn set_nonce(&mut self, nonce: String) {
-    self.nonce = nonce;
+  fn slots(&self) -> Vec<u8> {
+    self
+      .sockets
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|listener| listener.slot)
+      .collect()
   }
 
-  fn recreate_socket(&mut self) {
-    let socket = Self::create_socket(None);
-    *self.socket.lock().unwrap() = socket;
+  fn is_running(&self) -> bool {
+    self.running.load(Ordering::Relaxed)
   }
 
   /**
-   * Create a new thread that will recieve messages from the socket
+   * Create a new thread that will recieve messages from the pipes
    */
   fn start(&mut self) {
     let connector = self.clone();
 
-    std::thread::spawn(move || {
-      let socket = connector.socket.lock().unwrap();
+    for listener in self.sockets.lock().unwrap().iter() {
+      if let Err(err) = listener
+        .socket
+        .set_nonblocking(ListenerNonblockingMode::Accept)
+      {
+        log!("[IPC] Failed to set socket to non-blocking: {}", err);
+        return;
+      }
+    }
 
-      for stream in socket.incoming() {
-        // Little baby delay to keep things smooth
-        std::thread::sleep(std::time::Duration::from_millis(5));
+    self.running.store(true, Ordering::Relaxed);
 
-        let mut clone = connector.clone();
+    let handle = thread::spawn(move || {
+      let mut last_probe = Instant::now();
 
-        match stream {
-          Ok(mut stream) => {
-            log!("[IPC] Incoming stream...");
-            std::thread::spawn(move || handle_stream(&mut clone, &mut stream));
+      while connector.is_running() {
+        if let Some(interval) = connector.reprobe_interval {
+          if last_probe.elapsed() >= interval {
+            connector.claim_first_slot();
+            last_probe = Instant::now();
           }
-          Err(err) => {
-            log!("[IPC] Error: {}", err);
-            break;
+        }
+
+        let streams: Vec<_> = connector
+          .sockets
+          .lock()
+          .unwrap()
+          .iter()
+          .filter_map(|listener| match listener.socket.accept() {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => None,
+            result => Some(result),
+          })
+          .collect();
+
+        if streams.is_empty() {
+          thread::sleep(Duration::from_millis(50));
+          continue;
+        }
+
+        for stream in streams {
+          match stream {
+            Ok(stream) => {
+              log!("[IPC] Incoming stream...");
+
+              // Pipes can't time out reads, so the handler polls them with poll_read() instead
+              let mut clone = connector.clone();
+              let handle = thread::spawn(move || handle_stream(&mut clone, stream));
+
+              // Forget about handlers that already finished so the list doesn't grow forever
+              let mut threads = connector.threads.lock().unwrap();
+              threads.retain(|thread| !thread.is_finished());
+              threads.push(handle);
+            }
+            Err(err) => log!("[IPC] Error: {}", err),
           }
         }
       }
+
+      log!("[IPC] Stopped accepting connections");
     });
+
+    self.threads.lock().unwrap().push(handle);
+  }
+
+  /**
+   * Stop accepting connections, wait for every stream handler to exit, then close the pipes
+   */
+  fn stop(&mut self) {
+    self.running.store(false, Ordering::Relaxed);
+    join_all(&self.threads, "IPC");
+
+    self.sockets.lock().unwrap().clear();
+  }
+
+  fn max_frame_size(&self) -> usize {
+    self.max_frame_size
   }
 
   fn event_sender(&mut self) -> &mut mpsc::Sender<ActivityCmd> {
     &mut self.event_sender
   }
+
+  fn event_bus(&self) -> &EventBus {
+    &self.events
+  }
+
+  fn ready(&self) -> &ReadyPayload {
+    &self.ready
+  }
 }
 
 impl IpcConnector {
   /**
-   * Create a socket and return a new IpcConnector
+   * Create the pipes and return a new IpcConnector
    */
-  pub fn new(event_sender: mpsc::Sender<ActivityCmd>) -> Self {
-    Self {
-      socket: Arc::new(Mutex::new(Self::create_socket(None))),
-      did_handshake: false,
-      client_id: "".to_string(),
-      pid: 0,
-      nonce: "".to_string(),
+  #[allow(clippy::too_many_arguments)]
+  pub fn new(
+    event_sender: mpsc::Sender<ActivityCmd>,
+    events: EventBus,
+    ready: ReadyPayload,
+    socket_dir: Option<&Path>,
+    socket_prefix: &str,
+    slot_count: u8,
+    reprobe_interval: Option<Duration>,
+    _sandbox_links: bool,
+    max_frame_size: usize,
+  ) -> Result<Self, Error> {
+    let pipe_base = get_socket_path(socket_dir, socket_prefix);
+    let slot_count = slot_count.clamp(1, IPC_SLOTS);
+    let sockets = Self::create_sockets(&pipe_base, slot_count)?;
+
+    Ok(Self {
+      sockets: Arc::new(Mutex::new(sockets)),
+      pipe_base,
+      slot_count,
+      reprobe_interval,
+      max_frame_size,
+      running: Arc::new(AtomicBool::new(false)),
+      threads: Arc::new(Mutex::new(vec![])),
+      connections: IpcConnections::default(),
       event_sender,
+      events,
+      ready,
+    })
+  }
+
+  /**
+   * Bind the first `slot_count` free slots
+   */
+  fn create_sockets(pipe_base: &str, slot_count: u8) -> Result<Vec<BoundListener>, Error> {
+    let mut sockets = vec![];
+    let mut last_err = None;
+
+    for slot in 0..IPC_SLOTS {
+      if sockets.len() >= slot_count as usize {
+        break;
+      }
+
+      match Self::create_socket(pipe_base, slot) {
+        Ok(listener) => sockets.push(listener),
+        Err(err) => last_err = Some(err),
+      }
+    }
+
+    if sockets.is_empty() {
+      return Err(Error::IpcSocketUnavailable(last_err.unwrap_or_else(|| {
+        std::io::Error::new(ErrorKind::AddrInUse, "no IPC slot available")
+      })));
     }
+
+    Ok(sockets)
   }
 
-  fn create_socket(tries: Option<u8>) -> Listener {
-    // Define the path to the named pipe
-    let pipe_path = r"\\.\pipe\discord-ipc";
+  /**
+   * Try to take over slot 0 if someone else had it, giving up our highest slot to stay within `slot_count`
+   */
+  fn claim_first_slot(&self) {
+    if self.slots().contains(&0) {
+      return;
+    }
 
-    // Append tried number to name if applicable
-    let pipe_path = match tries {
-      Some(tries) => format!("{}-{}", pipe_path, tries),
-      None => format!("{}-{}", pipe_path, 0),
+    let listener = match Self::create_socket(&self.pipe_base, 0) {
+      Ok(listener) => listener,
+      Err(_) => return,
     };
 
+    if let Err(err) = listener
+      .socket
+      .set_nonblocking(ListenerNonblockingMode::Accept)
+    {
+      log!("[IPC] Failed to set socket to non-blocking: {}", err);
+      return;
+    }
+
+    log!("[IPC] Claimed slot 0");
+
+    let mut sockets = self.sockets.lock().unwrap();
+    sockets.insert(0, listener);
+    sockets.truncate(self.slot_count as usize);
+  }
+
+  fn create_socket(pipe_base: &str, slot: u8) -> std::io::Result<BoundListener> {
+    let pipe_path = format!("{}-{}", pipe_base, slot);
+
     let listener = ListenerOptions::new()
-      .name(pipe_path.clone().to_fs_name::<NamedPipe>().unwrap())
+      .name(pipe_path.clone().to_fs_name::<NamedPipe>()?)
       .security_descriptor(SecurityDescriptor::default());
 
     let socket = match listener.create_sync() {
       Ok(socket) => socket,
       Err(err) => {
         log!("[IPC] Failed to create IPC socket: {}", err);
-
-        if tries.unwrap_or(0) < 9 {
-          return Self::create_socket(Some(tries.unwrap_or(0) + 1));
-        } else {
-          panic!("[IPC] Failed to create socket: {}", err);
-        }
+        return Err(err);
       }
     };
 
     log!("[IPC] Created IPC socket: {}", pipe_path);
 
-    socket
+    Ok(BoundListener { socket, slot })
   }
 }
@@ -1,8 +1,12 @@
 pub mod client_connector;
+pub mod etf;
+pub mod invites;
 pub mod ipc_utils;
 pub mod process;
 pub mod utils;
 pub mod websocket;
+mod wine;
+mod ws_server;
 
 #[cfg(target_os = "windows")]
 pub mod ipc_win;
@@ -1,18 +1,22 @@
 use aho_corasick::{AhoCorasick, PatternID};
-use std::sync::atomic::AtomicBool;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::Duration;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::vec;
 
-#[cfg(not(target_os = "linux"))]
-use sysinfo::System;
-
 use crate::log;
+use crate::process_source::ProcessSource;
+use crate::AdaptiveScanConfig;
 use crate::ProcessCallback;
 
-use super::super::DetectableActivity;
+use super::utils::join_all;
+use super::wine::normalize_process;
+
+use crate::detection::{DetectableActivity, ExecutableOsFilter};
 
 #[derive(Default, Clone)]
 pub struct ProcessScanState {
@@ -24,23 +28,139 @@ pub struct ProcessEventListeners {
   pub on_process_scan_complete: Option<Arc<Mutex<ProcessCallback>>>,
 }
 
-#[derive(Clone)]
-pub struct Exec {
-  pid: u64,
-  path: String,
-  arguments: Option<String>,
+fn now_millis() -> u64 {
+  std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .map(|duration| duration.as_millis() as u64)
+    .unwrap_or_default()
+}
+
+/**
+ * Decides when the next scan should happen
+ */
+struct ScanSchedule {
+  interval: Duration,
+  adaptive: Option<AdaptiveScanConfig>,
+  next_scan: Instant,
+  fast_until: Option<Instant>,
+  last_change: Instant,
+}
+
+impl ScanSchedule {
+  fn new(interval: Duration, adaptive: Option<AdaptiveScanConfig>) -> Self {
+    let now = Instant::now();
+
+    ScanSchedule {
+      interval,
+      adaptive,
+      next_scan: now,
+      fast_until: None,
+      last_change: now,
+    }
+  }
+
+  fn speed_up(&mut self) {
+    let now = Instant::now();
+    self.last_change = now;
+
+    if let Some(adaptive) = &self.adaptive {
+      self.fast_until = Some(now + adaptive.fast_period);
+    }
+  }
+
+  fn current_interval(&self) -> Duration {
+    let Some(adaptive) = &self.adaptive else {
+      return self.interval;
+    };
+
+    if self.fast_until.is_some_and(|until| Instant::now() < until) {
+      adaptive.fast_interval
+    } else if on_battery() || self.last_change.elapsed() >= adaptive.idle_after {
+      adaptive.slow_interval
+    } else {
+      self.interval
+    }
+  }
+
+  fn reschedule(&mut self) {
+    self.next_scan = Instant::now() + self.current_interval();
+  }
+}
+
+/**
+ * Whether the system is running off a battery. Only known on Linux, everywhere else this is always false.
+ */
+#[cfg(target_os = "linux")]
+fn on_battery() -> bool {
+  let Ok(supplies) = std::fs::read_dir("/sys/class/power_supply") else {
+    return false;
+  };
+
+  // On battery if there is a mains supply and none of them are plugged in
+  let mut has_mains = false;
+
+  for supply in supplies.flatten() {
+    let path = supply.path();
+    let is_mains =
+      std::fs::read_to_string(path.join("type")).is_ok_and(|kind| kind.trim() == "Mains");
+
+    if !is_mains {
+      continue;
+    }
+
+    has_mains = true;
+
+    if std::fs::read_to_string(path.join("online")).is_ok_and(|online| online.trim() == "1") {
+      return false;
+    }
+  }
+
+  has_mains
+}
+
+#[cfg(not(target_os = "linux"))]
+fn on_battery() -> bool {
+  false
 }
 
 #[derive(Clone)]
-pub struct ProcessDetectedEvent {
-  pub activity: Arc<DetectableActivity>,
+pub enum ProcessDetectedEvent {
+  /// A detectable game that wasn't running during the previous scan
+  Started(Arc<DetectableActivity>),
+  /// A game from the previous scan that is no longer running
+  Stopped(Arc<DetectableActivity>),
+}
+
+/// A detected game, along with every running process that matched it
+struct RunningGame {
+  activity: Arc<DetectableActivity>,
+  /// Pids and start times, so a reused pid isn't mistaken for the process that had it before
+  processes: HashSet<(u64, Option<u64>)>,
+}
+
+/**
+ * Whether `activity` comes from a process that started before `other`'s. Processes with an unknown start time
+ * count as the newest, and ties go to the lowest pid, so the answer never depends on the order processes are listed
+ */
+fn started_before(activity: &DetectableActivity, other: &DetectableActivity) -> bool {
+  let key = |activity: &DetectableActivity| {
+    (
+      activity.timestamp.unwrap_or(u64::MAX),
+      activity.pid.unwrap_or_default(),
+    )
+  };
+
+  key(activity) < key(other)
 }
 
 #[derive(Clone)]
 pub struct ProcessServer {
-  detected_list: Arc<Mutex<Vec<Arc<DetectableActivity>>>>,
+  /// Games found by the last scan, by id
+  detected_list: Arc<Mutex<BTreeMap<String, RunningGame>>>,
   custom_detectables: Arc<Mutex<Vec<Arc<DetectableActivity>>>>,
   scanning: Arc<AtomicBool>,
+  running: Arc<AtomicBool>,
+  threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
 
   detectable_indexes: Arc<Mutex<Vec<[usize; 2]>>>,
   detectable_ac: Arc<Mutex<AhoCorasick>>,
@@ -53,8 +173,9 @@ pub struct ProcessServer {
 
   event_listeners: Arc<Mutex<ProcessEventListeners>>,
 
-  #[cfg(not(target_os = "linux"))]
-  sysinfo: Arc<Mutex<System>>,
+  source: Arc<Mutex<Box<dyn ProcessSource>>>,
+  schedule: Arc<Mutex<ScanSchedule>>,
+  os_filter: ExecutableOsFilter,
 }
 
 unsafe impl Sync for ProcessServer {}
@@ -64,14 +185,20 @@ impl ProcessServer {
     detectable: Vec<Arc<DetectableActivity>>,
     event_sender: mpsc::Sender<ProcessDetectedEvent>,
     event_listeners: ProcessEventListeners,
+    source: Arc<Mutex<Box<dyn ProcessSource>>>,
+    scan_interval: Duration,
+    adaptive_scan: Option<AdaptiveScanConfig>,
+    os_filter: ExecutableOsFilter,
   ) -> Self {
     log!("[Process Scanner] Building Aho-Corasick patterns for main detectable activities...");
-    let (ac, idx) = build_ac_patterns(&detectable);
+    let (ac, idx) = build_ac_patterns(&detectable, os_filter);
     log!("[Process Scanner] Done!");
 
     ProcessServer {
       scanning: Arc::new(AtomicBool::new(false)),
-      detected_list: Arc::new(Mutex::new(vec![])),
+      running: Arc::new(AtomicBool::new(false)),
+      threads: Arc::new(Mutex::new(vec![])),
+      detected_list: Arc::new(Mutex::new(BTreeMap::new())),
       custom_detectables: Arc::new(Mutex::new(vec![])),
       detectable_list: detectable,
       event_sender,
@@ -85,15 +212,15 @@ impl ProcessServer {
       // Event listeners
       event_listeners: Arc::new(Mutex::new(event_listeners)),
 
-      // sysinfo System
-      #[cfg(not(target_os = "linux"))]
-      sysinfo: Arc::new(Mutex::new(System::new())),
+      source,
+      schedule: Arc::new(Mutex::new(ScanSchedule::new(scan_interval, adaptive_scan))),
+      os_filter,
     }
   }
 
   fn update_custom_detectables(&self) {
     log!("[Process Scanner] Updating Aho-Corasick patterns for custom detectable activities...");
-    let (ac, idx) = build_ac_patterns(&self.custom_detectables.lock().unwrap());
+    let (ac, idx) = build_ac_patterns(&self.custom_detectables.lock().unwrap(), self.os_filter);
     if !idx.is_empty() {
       *self.custom_detectable_ac.lock().unwrap() = Some(ac);
     } else {
@@ -123,196 +250,153 @@ impl ProcessServer {
   }
 
   pub fn start(&self) {
-    let wait_time = Duration::from_secs(10);
     let clone = self.clone();
 
     self.update_custom_detectables();
+    self.running.store(true, Ordering::Relaxed);
 
-    std::thread::spawn(move || {
-      // Run the process scan repeatedly (every 3 seconds)
-      loop {
-        let detected = match clone.scan_for_processes() {
-          Ok(detected) => detected,
-          Err(err) => {
-            log!("[Process Scanner] Error while scanning processes: {}", err);
-            std::thread::sleep(wait_time);
-            continue;
-          }
-        };
-        let mut new_game_detected = false;
-
-        // If the detected list has changed, send only the first element
-        if !detected.is_empty() {
-          let detected_list = clone.detected_list.lock().unwrap();
-
-          // If the detected list is empty, send the first element
-          if detected_list.is_empty() {
-            new_game_detected = true;
-            clone
-              .event_sender
-              .send(ProcessDetectedEvent {
-                activity: detected[0].clone(),
-              })
-              .unwrap();
-          } else {
-            // If the detected list is not empty, check if the first element is different
-            if detected[0].id != detected_list[0].id {
-              new_game_detected = true;
-            }
-
-            clone
-              .event_sender
-              .send(ProcessDetectedEvent {
-                activity: detected[0].clone(),
-              })
-              .unwrap();
-          }
-        }
-
-        // If there are no detected processes, send an empty message
-        if detected.is_empty() {
-          clone
-            .event_sender
-            .send(ProcessDetectedEvent {
-              activity: Arc::new(DetectableActivity {
-                bot_public: None,
-                bot_require_code_grant: None,
-                cover_image: None,
-                description: None,
-                developers: None,
-                executables: None,
-                flags: None,
-                guild_id: None,
-                hook: false,
-                icon: None,
-                id: "null".to_string(),
-                name: "".to_string(),
-                publishers: None,
-                rpc_origins: None,
-                splash: None,
-                third_party_skus: None,
-                type_field: None,
-                verify_key: None,
-                primary_sku_id: None,
-                slug: None,
-                aliases: None,
-                overlay: None,
-                overlay_compatibility_hook: None,
-                privacy_policy_url: None,
-                terms_of_service_url: None,
-                eula_id: None,
-                deeplink_uri: None,
-                tags: None,
-                pid: None,
-                timestamp: None,
-              }),
-            })
-            .unwrap();
-        }
+    let handle = std::thread::spawn(move || {
+      // Run the process scan repeatedly, whenever the schedule says it is due. Manual scans push the next one back
+      while clone.running.load(Ordering::Relaxed) {
+        let next_scan = clone.schedule.lock().unwrap().next_scan;
+        let now = Instant::now();
 
-        if new_game_detected {
-          // Set the detected list to the new list
-          *clone.detected_list.lock().unwrap() = detected;
+        if now < next_scan {
+          std::thread::sleep((next_scan - now).min(Duration::from_millis(50)));
+          continue;
         }
 
-        std::thread::sleep(wait_time);
+        clone.scan_now();
       }
+
+      log!("[Process Scanner] Stopped");
     });
+
+    self.threads.lock().unwrap().push(handle);
   }
 
-  #[cfg(not(target_os = "linux"))]
-  pub fn process_list(&self) -> Result<Vec<Exec>, Box<dyn std::error::Error>> {
-    use std::path::Path;
-    use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, UpdateKind};
-
-    let mut processes = Vec::new();
-    let mut sys = self.sysinfo.lock().unwrap();
-    sys.refresh_processes_specifics(
-      ProcessesToUpdate::All,
-      true,
-      ProcessRefreshKind::nothing()
-        .with_exe(UpdateKind::OnlyIfNotSet)
-        .with_cmd(UpdateKind::OnlyIfNotSet),
-    );
+  /**
+   * Scan, send events for any games that started or stopped, and schedule the next scan
+   */
+  pub fn scan_now(&self) {
+    if self.scanning.swap(true, Ordering::Relaxed) {
+      log!("[Process Scanner] Scanning already in progress");
+      return;
+    }
 
-    for proc in sys.processes() {
-      let mut cmd = proc.1.cmd().iter();
-      processes.push(Exec {
-        pid: proc.0.to_string().parse::<u64>()?,
-        path: proc.1.exe().unwrap_or(Path::new("")).display().to_string(),
-        arguments: cmd.next().map(|_| {
-          cmd
-            .map(|x| x.to_string_lossy())
-            .collect::<Vec<_>>()
-            .join(" ")
-        }),
-      });
+    match self.scan_for_processes() {
+      Ok(detected) => {
+        if self.diff_detected(detected) {
+          self.schedule.lock().unwrap().speed_up();
+        }
+      }
+      Err(err) => {
+        log!("[Process Scanner] Error while scanning processes: {}", err);
+      }
     }
 
-    Ok(processes)
+    self.schedule.lock().unwrap().reschedule();
+    self.scanning.store(false, Ordering::Relaxed);
   }
 
-  #[cfg(target_os = "linux")]
-  pub fn process_list() -> Result<Vec<Exec>, Box<dyn std::error::Error>> {
-    use std::fs;
+  /**
+   * Compare the games found by a scan against the previous scan, and send an event for each game that started or stopped.
+   * A game keeps running for as long as any of its processes from the previous scan is still around, and counts as
+   * restarted when all of them were replaced. Returns whether any game started or stopped.
+   */
+  fn diff_detected(&self, detected: Vec<Arc<DetectableActivity>>) -> bool {
+    let mut detected_list = self.detected_list.lock().unwrap();
+
+    // Group matching processes by game, represented by whichever process started first
+    let mut current: BTreeMap<String, RunningGame> = BTreeMap::new();
+    for activity in detected {
+      let process = (activity.pid.unwrap_or_default(), activity.timestamp);
+
+      match current.get_mut(&activity.id) {
+        Some(game) => {
+          game.processes.insert(process);
+
+          if started_before(&activity, &game.activity) {
+            game.activity = activity;
+          }
+        }
+        None => {
+          current.insert(
+            activity.id.clone(),
+            RunningGame {
+              activity,
+              processes: HashSet::from([process]),
+            },
+          );
+        }
+      }
+    }
+
+    let mut events = vec![];
+
+    for (id, previous) in detected_list.iter() {
+      let still_running = current
+        .get(id)
+        .is_some_and(|game| !game.processes.is_disjoint(&previous.processes));
 
-    let proc_list = fs::read_dir("/proc")?.filter(|e| {
-      if let Ok(entry) = e {
-        // Only if we can parse this as a number
-        return entry.file_name().to_str().unwrap().parse::<u64>().is_ok();
+      if !still_running {
+        log!("[Process Scanner] Game stopped: {}", previous.activity.name);
+        events.push(ProcessDetectedEvent::Stopped(previous.activity.clone()));
       }
+    }
 
-      false
-    });
-    let mut processes = Vec::new();
-
-    for entry in proc_list {
-      let entry = entry?;
-      let path = entry.path();
-
-      if let Ok(cmdline) = fs::read_to_string(path.join("cmdline")) {
-        if !cmdline.is_empty() {
-          let mut cmd_iter = cmdline.split('\0');
-          let (cmd_path, cmd_args) = (
-            cmd_iter.next().unwrap_or("").to_string(),
-            cmd_iter.collect::<Vec<_>>().join(" "),
-          );
-          processes.push(Exec {
-            pid: path
-              .file_name()
-              .ok_or("Invalid path")?
-              .to_str()
-              .ok_or("Invalid path")?
-              .parse::<u64>()?,
-            path: cmd_path,
-            arguments: if cmd_args.is_empty() {
-              None
-            } else {
-              Some(cmd_args)
-            },
-          });
+    for (id, game) in current.iter_mut() {
+      match detected_list.get(id) {
+        // Keep reporting what was sent when it started, so the start time doesn't move as processes come and go
+        Some(previous) if !game.processes.is_disjoint(&previous.processes) => {
+          game.activity = previous.activity.clone();
+        }
+        _ => {
+          // Fall back to now if we couldn't tell when the process started
+          if game.activity.timestamp.is_none() {
+            let mut activity = (*game.activity).clone();
+            activity.timestamp = Some(now_millis());
+            game.activity = Arc::new(activity);
+          }
+
+          log!("[Process Scanner] Game started: {}", game.activity.name);
+          events.push(ProcessDetectedEvent::Started(game.activity.clone()));
         }
       }
     }
 
-    Ok(processes)
+    *detected_list = current;
+    drop(detected_list);
+
+    let changed = !events.is_empty();
+
+    for event in events {
+      if self.event_sender.send(event).is_err() {
+        log!("[Process Scanner] Event receiver is gone, dropping event");
+      }
+    }
+
+    changed
+  }
+
+  /**
+   * Stop the scanning thread and wait for it to exit
+   */
+  pub fn stop(&self) {
+    self.running.store(false, Ordering::Relaxed);
+    join_all(&self.threads, "Process Scanner");
+
+    // Nothing is running anymore as far as anyone listening is concerned
+    self.diff_detected(vec![]);
   }
 
   pub fn scan_for_processes(
     &self,
   ) -> Result<Vec<Arc<DetectableActivity>>, Box<dyn std::error::Error>> {
-    #[cfg(not(target_os = "linux"))]
-    let processes = self.process_list()?;
-    #[cfg(target_os = "linux")]
-    let processes = ProcessServer::process_list()?;
+    let processes = self.source.lock().unwrap().processes()?;
 
     log!("[Process Scanner] Process scan triggered");
 
-    if self.scanning.load(std::sync::atomic::Ordering::Relaxed) {
-      log!("[Process Scanner] Scanning already in progress");
-      return Err("Scanning already in progress".into());
-    }
-
     let mut obs_open = false;
 
     let ac = self.detectable_ac.lock().unwrap();
@@ -323,16 +407,9 @@ impl ProcessServer {
     let mut detected_list: Vec<Arc<DetectableActivity>> = processes
       .iter()
       .filter_map(|process| {
-        // Process path (but consistent slashes, so we can compare properly)
-        let mut process_path = process.path.to_ascii_lowercase();
-
-        if process_path.contains('\\') {
-          process_path = process_path.replace('\\', "/");
-        }
-
-        if !process_path.starts_with('/') {
-          process_path.insert(0, '/');
-        }
+        // Process path (but consistent slashes, so we can compare properly), with any Wine/Proton wrapping removed
+        let (process_path, arguments) =
+          normalize_process(&process.path, process.arguments.as_deref());
 
         if !obs_open && (process_path.contains("obs64") || process_path.contains("streamlabs")) {
           obs_open = true;
@@ -370,8 +447,7 @@ impl ProcessServer {
           // Other games might provide arguments but not necessary be checked
           // like Left 4 Dead 2: { arguments: "-game left4dead2", is_launcher: false, name: "left 4 dead 2/left4dead2.exe", … }
           if executable.name.starts_with(">")
-            && !process
-              .arguments
+            && !arguments
               .as_ref()
               .is_some_and(|args| args.contains(exec_args))
           {
@@ -381,13 +457,7 @@ impl ProcessServer {
 
         let mut new_activity = (**obj).clone();
         new_activity.pid = Some(process.pid);
-        new_activity.timestamp = Some(format!(
-          "{:?}",
-          std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_millis()
-        ));
+        new_activity.timestamp = process.start_time;
         Some(Arc::new(new_activity))
       })
       .collect();
@@ -410,14 +480,17 @@ impl ProcessServer {
   }
 }
 
-fn build_ac_patterns(detectables: &[Arc<DetectableActivity>]) -> (AhoCorasick, Vec<[usize; 2]>) {
+fn build_ac_patterns(
+  detectables: &[Arc<DetectableActivity>],
+  os_filter: ExecutableOsFilter,
+) -> (AhoCorasick, Vec<[usize; 2]>) {
   let mut exe_patterns: Vec<String> = Vec::new();
   let mut exe_indexes: Vec<[usize; 2]> = Vec::new();
 
   for (activity_index, activity) in detectables.iter().enumerate() {
     if let Some(executables) = &activity.executables {
       for (exe_index, executable) in executables.iter().enumerate() {
-        if executable.is_launcher {
+        if executable.is_launcher || !os_filter.allows(executable) {
           continue;
         }
 
@@ -450,3 +523,311 @@ fn build_ac_patterns(detectables: &[Arc<DetectableActivity>]) -> (AhoCorasick, V
 
 //   name.to_owned()
 // }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::detection::CURRENT_OS;
+  use crate::process_source::{Exec, FakeProcessSource};
+
+  /// What an event was about: started or not, the game's id, and the pid and start time it was reported with
+  type EventSummary = (bool, String, Option<u64>, Option<u64>);
+
+  fn detectable(id: &str, executables: &[(&str, &str)]) -> Arc<DetectableActivity> {
+    let executables: Vec<serde_json::Value> = executables
+      .iter()
+      .map(|(name, os)| serde_json::json!({ "is_launcher": false, "name": name, "os": os }))
+      .collect();
+
+    Arc::new(
+      serde_json::from_value(serde_json::json!({
+        "id": id,
+        "name": format!("Game {id}"),
+        "hook": false,
+        "executables": executables,
+      }))
+      .unwrap(),
+    )
+  }
+
+  fn process(pid: u64, path: &str, start_time: Option<u64>) -> Exec {
+    Exec {
+      pid,
+      path: path.to_string(),
+      start_time,
+      ..Default::default()
+    }
+  }
+
+  fn server(
+    detectables: Vec<Arc<DetectableActivity>>,
+    os_filter: ExecutableOsFilter,
+  ) -> (
+    ProcessServer,
+    FakeProcessSource,
+    mpsc::Receiver<ProcessDetectedEvent>,
+  ) {
+    let source = FakeProcessSource::default();
+    let (event_sender, events) = mpsc::channel();
+    let server = ProcessServer::new(
+      detectables,
+      event_sender,
+      ProcessEventListeners::default(),
+      Arc::new(Mutex::new(Box::new(source.clone()))),
+      Duration::from_secs(10),
+      None,
+      os_filter,
+    );
+
+    (server, source, events)
+  }
+
+  fn scan(
+    server: &ProcessServer,
+    source: &FakeProcessSource,
+    events: &mpsc::Receiver<ProcessDetectedEvent>,
+    processes: Vec<Exec>,
+  ) -> Vec<EventSummary> {
+    source.set_processes(processes);
+    server.scan_now();
+
+    events
+      .try_iter()
+      .map(|event| match event {
+        ProcessDetectedEvent::Started(activity) => {
+          (true, activity.id.clone(), activity.pid, activity.timestamp)
+        }
+        ProcessDetectedEvent::Stopped(activity) => {
+          (false, activity.id.clone(), activity.pid, activity.timestamp)
+        }
+      })
+      .collect()
+  }
+
+  fn started(id: &str, pid: u64, timestamp: u64) -> EventSummary {
+    (true, id.to_string(), Some(pid), Some(timestamp))
+  }
+
+  fn stopped(id: &str, pid: u64, timestamp: u64) -> EventSummary {
+    (false, id.to_string(), Some(pid), Some(timestamp))
+  }
+
+  #[test]
+  fn scans_the_configured_process_source() {
+    let (server, source, events) = server(
+      vec![detectable("1", &[("game.exe", CURRENT_OS)])],
+      ExecutableOsFilter::CurrentOs,
+    );
+
+    assert_eq!(scan(&server, &source, &events, vec![]), vec![]);
+
+    // Whatever the source lists on the next scan is what gets matched
+    let game = process(10, "/games/game.exe", Some(1000));
+    assert_eq!(
+      scan(&server, &source, &events, vec![game]),
+      vec![started("1", 10, 1000)]
+    );
+    assert_eq!(
+      scan(&server, &source, &events, vec![]),
+      vec![stopped("1", 10, 1000)]
+    );
+  }
+
+  #[test]
+  fn games_start_and_stop_independently() {
+    let (server, source, events) = server(
+      vec![
+        detectable("1", &[("one.exe", CURRENT_OS)]),
+        detectable("2", &[("two.exe", CURRENT_OS)]),
+      ],
+      ExecutableOsFilter::CurrentOs,
+    );
+    let one = process(10, "/games/one.exe", Some(1000));
+    let two = process(20, "/games/two.exe", Some(2000));
+
+    assert_eq!(
+      scan(&server, &source, &events, vec![one.clone(), two.clone()]),
+      vec![started("1", 10, 1000), started("2", 20, 2000)]
+    );
+    assert_eq!(
+      scan(&server, &source, &events, vec![two.clone(), one.clone()]),
+      vec![]
+    );
+
+    // Closing one game leaves the other alone
+    assert_eq!(
+      scan(&server, &source, &events, vec![two.clone()]),
+      vec![stopped("1", 10, 1000)]
+    );
+    assert_eq!(
+      scan(&server, &source, &events, vec![one, two]),
+      vec![started("1", 10, 1000)]
+    );
+
+    server.stop();
+    assert_eq!(
+      events.try_iter().count(),
+      2,
+      "stopping the server stops every game"
+    );
+  }
+
+  #[test]
+  fn games_with_several_processes_stay_running() {
+    let (server, source, events) = server(
+      vec![detectable("1", &[("game.exe", CURRENT_OS)])],
+      ExecutableOsFilter::CurrentOs,
+    );
+    let launcher = process(10, "/games/launcher/game.exe", Some(1000));
+    let game = process(11, "/games/bin/game.exe", Some(2000));
+
+    // Reported as the oldest process, whatever order they are listed in
+    assert_eq!(
+      scan(
+        &server,
+        &source,
+        &events,
+        vec![game.clone(), launcher.clone()]
+      ),
+      vec![started("1", 10, 1000)]
+    );
+    assert_eq!(
+      scan(&server, &source, &events, vec![launcher, game.clone()]),
+      vec![]
+    );
+
+    // The launcher exiting doesn't stop the game, or change when it started
+    assert_eq!(scan(&server, &source, &events, vec![game.clone()]), vec![]);
+
+    // Every process being replaced between scans is a restart
+    let restarted = process(12, "/games/bin/game.exe", Some(5000));
+    assert_eq!(
+      scan(&server, &source, &events, vec![restarted]),
+      vec![stopped("1", 10, 1000), started("1", 12, 5000)]
+    );
+
+    // So is a new process that happens to get the old one's pid
+    let reused_pid = process(12, "/games/bin/game.exe", Some(9000));
+    assert_eq!(
+      scan(&server, &source, &events, vec![reused_pid]),
+      vec![stopped("1", 12, 5000), started("1", 12, 9000)]
+    );
+  }
+
+  #[test]
+  fn start_time_comes_from_the_process() {
+    let (server, source, events) = server(
+      vec![
+        detectable("1", &[("one.exe", CURRENT_OS)]),
+        detectable("2", &[("two.exe", CURRENT_OS)]),
+      ],
+      ExecutableOsFilter::CurrentOs,
+    );
+
+    let before = now_millis();
+    let detected = scan(
+      &server,
+      &source,
+      &events,
+      vec![
+        process(10, "/games/one.exe", Some(1234)),
+        process(20, "/games/two.exe", None),
+      ],
+    );
+
+    assert_eq!(detected[0], started("1", 10, 1234));
+
+    // Unknown start times fall back to when the game was first seen, and stay that way
+    let fallback = detected[1].3.unwrap();
+    assert!(fallback >= before && fallback <= now_millis());
+    assert_eq!(
+      scan(
+        &server,
+        &source,
+        &events,
+        vec![
+          process(10, "/games/one.exe", Some(1234)),
+          process(20, "/games/two.exe", None),
+        ],
+      ),
+      vec![]
+    );
+  }
+
+  #[test]
+  fn os_filter_decides_which_executables_match() {
+    let other_os = if CURRENT_OS == "darwin" {
+      "linux"
+    } else {
+      "darwin"
+    };
+    let detectables = vec![
+      detectable("native", &[("native", CURRENT_OS)]),
+      detectable("windows", &[("windows.exe", "win32")]),
+      detectable("other", &[("other", other_os)]),
+    ];
+    let processes = vec![
+      process(1, "/games/native", Some(1)),
+      process(2, "/games/windows.exe", Some(2)),
+      process(3, "/games/other", Some(3)),
+    ];
+
+    let detected_ids = |os_filter: ExecutableOsFilter| -> Vec<String> {
+      let (server, source, events) = server(detectables.clone(), os_filter);
+      let mut ids: Vec<String> = scan(&server, &source, &events, processes.clone())
+        .into_iter()
+        .map(|(_, id, _, _)| id)
+        .collect();
+      ids.sort();
+      ids
+    };
+
+    let mut current_os = vec!["native"];
+    if CURRENT_OS == "win32" {
+      current_os.push("windows");
+    }
+
+    assert_eq!(detected_ids(ExecutableOsFilter::CurrentOs), current_os);
+    assert_eq!(
+      detected_ids(ExecutableOsFilter::CurrentOsAndWin32),
+      vec!["native", "windows"]
+    );
+    assert_eq!(
+      detected_ids(ExecutableOsFilter::All),
+      vec!["native", "other", "windows"]
+    );
+  }
+
+  #[test]
+  fn only_games_starting_or_stopping_speed_up_scans() {
+    let (server, source, events) = server(
+      vec![detectable("1", &[("game.exe", CURRENT_OS)])],
+      ExecutableOsFilter::CurrentOs,
+    );
+    *server.schedule.lock().unwrap() =
+      ScanSchedule::new(Duration::from_secs(10), Some(AdaptiveScanConfig::default()));
+    let last_change = || server.schedule.lock().unwrap().last_change;
+    let before = last_change();
+
+    // Short-lived processes come and go all the time, none of which are games
+    let shell = process(1, "/usr/bin/bash", Some(1000));
+    scan(&server, &source, &events, vec![shell.clone()]);
+    scan(
+      &server,
+      &source,
+      &events,
+      vec![shell.clone(), process(2, "/usr/bin/ls", Some(2000))],
+    );
+    assert_eq!(last_change(), before);
+    assert!(server.schedule.lock().unwrap().fast_until.is_none());
+
+    scan(
+      &server,
+      &source,
+      &events,
+      vec![shell, process(3, "/games/game.exe", Some(3000))],
+    );
+    assert!(last_change() > before);
+    assert!(server.schedule.lock().unwrap().fast_until.is_some());
+  }
+}
@@ -1,22 +1,156 @@
-pub static CONNECTION_REPONSE: &str = r#"
-{
-  "cmd": "DISPATCH",
-  "evt": "READY",
-  "data": {
-    "v": 1,
-    "user": {
-      "id": "1045800378228281345",
-      "username": "arRPC",
-      "discriminator": "0000",
-      "avatar": "cfefa4d9839fb4bdf030f91c2a13e95c",
-      "flags": 0,
-      "premium_type": 0
-    },
-    "config": {
-      "api_endpoint": "//discord.com/api",
-      "cdn_host": "cdn.discordapp.com",
-      "environment": "production"
+use std::{
+  sync::{Arc, Mutex},
+  thread::JoinHandle,
+};
+
+use serde_json::Value;
+
+use crate::{
+  cmd::{Ready, ReadyConfig, User},
+  log,
+};
+
+/**
+ * The READY dispatch sent to every client once it connects. Clones share the same user, so it can be changed
+ * while running.
+ */
+#[derive(Clone)]
+pub struct ReadyPayload(Arc<Mutex<Ready>>);
+
+impl ReadyPayload {
+  pub fn new(user: User, config: ReadyConfig) -> Self {
+    Self(Arc::new(Mutex::new(Ready { v: 1, user, config })))
+  }
+
+  /**
+   * Change the user sent to clients that connect from now on
+   */
+  pub fn set_user(&self, user: User) {
+    self.0.lock().unwrap().user = user;
+  }
+
+  pub fn response(&self) -> CommandResponse {
+    let data = serde_json::to_value(&*self.0.lock().unwrap()).unwrap_or_default();
+
+    CommandResponse::dispatch("READY", data)
+  }
+
+  pub fn message(&self) -> String {
+    serde_json::to_string(&self.response()).unwrap_or_default()
+  }
+}
+
+/// Events a client can SUBSCRIBE to
+pub const SUBSCRIBABLE_EVENTS: &[&str] = &[
+  "ACTIVITY_JOIN",
+  "ACTIVITY_SPECTATE",
+  "ACTIVITY_JOIN_REQUEST",
+];
+
+/// What gets sent back for each command. `evt` is null, except for errors.
+#[derive(serde::Serialize, Debug)]
+pub struct CommandResponse {
+  pub cmd: String,
+  pub data: Value,
+  pub evt: Option<String>,
+  pub nonce: Value,
+}
+
+impl CommandResponse {
+  pub fn new(cmd: String, data: Value, nonce: Value) -> Self {
+    Self {
+      cmd,
+      data,
+      evt: None,
+      nonce,
+    }
+  }
+
+  /**
+   * An event sent to a client that subscribed to it
+   */
+  pub fn dispatch(evt: &str, data: Value) -> Self {
+    Self {
+      cmd: "DISPATCH".to_string(),
+      data,
+      evt: Some(evt.to_string()),
+      nonce: Value::Null,
+    }
+  }
+
+  pub fn error(cmd: String, nonce: Value, code: RpcErrorCode, message: &str) -> Self {
+    Self {
+      cmd,
+      data: serde_json::json!({ "code": code as u32, "message": message }),
+      evt: Some("ERROR".to_string()),
+      nonce,
+    }
+  }
+
+  /**
+   * The last message sent before closing a connection that can't carry a close code itself
+   */
+  pub fn close(code: CloseCode, message: &str) -> Self {
+    Self {
+      cmd: "".to_string(),
+      data: serde_json::json!({ "code": code as u32, "message": message }),
+      evt: Some("ERROR".to_string()),
+      nonce: Value::Null,
+    }
+  }
+
+  /**
+   * An error for a message that isn't a valid command, keeping whatever `cmd` and `nonce` can be salvaged from it
+   */
+  pub fn invalid_payload(message: &str, reason: &str) -> Self {
+    let value = serde_json::from_str::<Value>(message).unwrap_or_default();
+
+    Self::error(
+      value["cmd"].as_str().unwrap_or_default().to_string(),
+      value["nonce"].clone(),
+      RpcErrorCode::InvalidPayload,
+      reason,
+    )
+  }
+}
+
+/// Discord's RPC error codes, sent in `data.code` of ERROR responses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RpcErrorCode {
+  UnknownError = 1000,
+  InvalidPayload = 4000,
+  InvalidCommand = 4002,
+  InvalidEvent = 4004,
+  InvalidInvite = 4011,
+}
+
+/// Codes a connection is closed with, matching the ones Discord uses
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseCode {
+  Normal = 1000,
+  Unsupported = 1003,
+  TooLarge = 1009,
+  InvalidClientId = 4000,
+  InvalidOrigin = 4001,
+  InvalidVersion = 4004,
+  InvalidEncoding = 4005,
+}
+
+/**
+ * Join every thread in the list, including any that get pushed while we are busy joining
+ */
+pub fn join_all(threads: &Mutex<Vec<JoinHandle<()>>>, name: &str) {
+  loop {
+    let handles = std::mem::take(&mut *threads.lock().unwrap());
+
+    if handles.is_empty() {
+      break;
+    }
+
+    for handle in handles {
+      if handle.join().is_err() {
+        log!("[{}] A worker thread panicked before shutdown", name);
+      }
     }
   }
 }
-"#;
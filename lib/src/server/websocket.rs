@@ -1,39 +1,117 @@
 use std::{
-  collections::HashMap,
-  sync::{mpsc, Arc, Mutex},
+  collections::{HashMap, HashSet},
+  net::IpAddr,
+  ops::Range,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc, Mutex,
+  },
+  thread::JoinHandle,
+  time::Duration,
 };
 
-use simple_websockets::{Event, EventHub, Message, Responder};
+use serde_json::Value;
+use simple_websockets::{Event, Message, Responder};
 
 use crate::{
-  cmd::{ActivityCmd, ActivityCmdArgs},
+  cmd::{ActivityCmd, ActivityCmdArgs, DeepLink},
+  events::{ActivitySource, EventBus, RPCEvent},
   log,
-  server::utils::CONNECTION_REPONSE,
+  server::etf,
+  server::invites::PendingInvites,
+  server::utils::{
+    join_all, CloseCode, CommandResponse, ReadyPayload, RpcErrorCode, SUBSCRIBABLE_EVENTS,
+  },
+  server::ws_server::WebsocketServer,
   url_params::get_url_params,
+  Error, OriginPolicy,
 };
 
-type ActivityResponder = (Option<ActivityCmd>, Responder);
+/// How a client wants its messages encoded, from the `encoding` URL param
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+  Json,
+  Etf,
+}
+
+impl Encoding {
+  fn from_param(param: &str) -> Option<Self> {
+    match param {
+      "json" => Some(Encoding::Json),
+      "etf" => Some(Encoding::Etf),
+      _ => None,
+    }
+  }
+
+  /**
+   * Turn a response into a message in this encoding
+   */
+  pub fn message(&self, response: &CommandResponse) -> Message {
+    match self {
+      Encoding::Json => Message::Text(serde_json::to_string(response).unwrap_or_default()),
+      Encoding::Etf => Message::Binary(etf::encode(
+        &serde_json::to_value(response).unwrap_or_default(),
+      )),
+    }
+  }
+}
+
+pub struct WebsocketClient {
+  /// The last SET_ACTIVITY the client sent
+  activity: Option<ActivityCmd>,
+  responder: Responder,
+  encoding: Encoding,
+  /// Events the client has SUBSCRIBEd to
+  subscriptions: HashSet<String>,
+}
 
 #[derive(Clone)]
 pub struct WebsocketConnector {
-  server: Arc<Mutex<Option<EventHub>>>,
-  pub clients: Arc<Mutex<HashMap<u64, ActivityResponder>>>,
+  server: Arc<Mutex<Option<WebsocketServer>>>,
+  pub clients: Arc<Mutex<HashMap<u64, WebsocketClient>>>,
+  running: Arc<AtomicBool>,
+  threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
 
   event_sender: mpsc::Sender<ActivityCmd>,
+  events: EventBus,
+  ready: ReadyPayload,
+  origin_policy: OriginPolicy,
+  pending_invites: PendingInvites,
 }
 
 impl WebsocketConnector {
-  pub fn new(event_sender: mpsc::Sender<ActivityCmd>) -> Self {
-    // Try starting websocket server on ports 6463 - 6472
-    for port in 6463..6472 {
-      match simple_websockets::launch(port) {
+  pub fn new(
+    event_sender: mpsc::Sender<ActivityCmd>,
+    events: EventBus,
+    ready: ReadyPayload,
+    origin_policy: OriginPolicy,
+    pending_invites: PendingInvites,
+    address: IpAddr,
+    ports: Range<u16>,
+  ) -> Result<Self, Error> {
+    if ports.is_empty() {
+      return Err(Error::InvalidConfig(format!(
+        "websocket_ports {}..{} is empty",
+        ports.start, ports.end
+      )));
+    }
+
+    // Try starting websocket server on each port in the range (6463 - 6471 by default)
+    for port in ports.clone() {
+      match WebsocketServer::launch(address, port) {
         Ok(server) => {
           log!("[Websocket] Server started on port {}", port);
-          return Self {
+          return Ok(Self {
             server: Arc::new(Mutex::new(Some(server))),
             clients: Arc::new(Mutex::new(HashMap::new())),
+            running: Arc::new(AtomicBool::new(false)),
+            threads: Arc::new(Mutex::new(vec![])),
             event_sender,
-          };
+            events,
+            ready,
+            origin_policy,
+            pending_invites,
+          });
         }
         Err(_) => {
           log!("[Websocket] Failed to start server on port {}", port);
@@ -42,51 +120,112 @@ impl WebsocketConnector {
     }
 
     log!("[Websocket] Failed to start server on any port");
-    std::process::exit(1);
+    Err(Error::NoAvailablePort(ports))
   }
 
-  pub fn start(&mut self, set_activity: bool, secondary_events: bool) {
-    let server = self
+  pub fn start(&mut self, set_activity: bool, secondary_events: bool) -> Result<(), Error> {
+    let mut server = self
       .server
       .lock()
       .unwrap()
       .take()
-      .expect("Websocket server already started");
+      .ok_or(Error::AlreadyStarted)?;
     let clients = self.clients.clone();
     let event_sender = self.event_sender.clone();
+    let events = self.events.clone();
+    let ready = self.ready.clone();
+    let origin_policy = self.origin_policy.clone();
+    let pending_invites = self.pending_invites.clone();
+    let running = self.running.clone();
+
+    self.running.store(true, Ordering::Relaxed);
+
+    let handle = std::thread::spawn(move || {
+      while running.load(Ordering::Relaxed) {
+        pending_invites.expire();
 
-    std::thread::spawn(move || {
-      let mut clients = clients.lock().unwrap();
+        let Some(event) = server.next_event() else {
+          std::thread::sleep(Duration::from_millis(50));
+          continue;
+        };
 
-      loop {
-        log!("[Websocket] Polling for events...");
+        // Only held while handling an event, so events can be dispatched to clients in between
+        let mut clients = clients.lock().unwrap();
 
-        match server.poll_event() {
+        let emitted = match event {
           Event::Connect(client_id, responder) => {
             let connection = responder.connection_details();
             let url_params = get_url_params(connection.uri.clone());
             let version = url_params.get("v").unwrap_or(&"0".to_string()).clone();
-            let encoding = url_params
+            let encoding_param = url_params
               .get("encoding")
               .unwrap_or(&"json".to_string())
               .clone();
+            let encoding = Encoding::from_param(&encoding_param);
+
+            let origin = connection
+              .headers
+              .get("origin")
+              .map(|origin| origin.to_str().unwrap_or_default().to_string());
 
             log!("[Websocket] Client {} connected", client_id);
 
-            if version != "1" || encoding != "json" {
-              log!("[Websocket] Invalid connection from client {}", client_id);
+            let rejection = if !origin_policy.allows(origin.as_deref()) {
+              Some((
+                CloseCode::InvalidOrigin,
+                format!("Invalid Origin: {}", origin.unwrap_or_default()),
+              ))
+            } else if version != "1" {
+              Some((
+                CloseCode::InvalidVersion,
+                format!("Invalid Version: {version}"),
+              ))
+            } else if encoding.is_none() {
+              Some((
+                CloseCode::InvalidEncoding,
+                format!("Invalid Encoding: {encoding_param}"),
+              ))
+            } else {
+              None
+            };
+
+            let encoding = encoding.unwrap_or(Encoding::Json);
+
+            if let Some((code, reason)) = rejection {
+              log!("[Websocket] Rejecting client {}: {}", client_id, reason);
+              // simple-websockets can only close without a status, so the close code goes in the ERROR payload
+              responder.send(encoding.message(&CommandResponse::close(code, &reason)));
+              responder.close();
               continue;
             }
 
-            responder.send(Message::Text(CONNECTION_REPONSE.to_string()));
+            responder.send(encoding.message(&ready.response()));
 
-            clients.insert(client_id, (None, responder));
+            clients.insert(
+              client_id,
+              WebsocketClient {
+                activity: None,
+                responder,
+                encoding,
+                subscriptions: HashSet::new(),
+              },
+            );
+
+            Some(RPCEvent::ClientConnected {
+              source: ActivitySource::Websocket,
+              client_id: url_params.get("client_id").cloned().unwrap_or_default(),
+            })
           }
           Event::Disconnect(client_id) => {
             log!("[Websocket] Client {} disconnected", client_id);
-            let responder = clients.remove(&client_id).unwrap();
 
-            handle_disconnect(client_id, &event_sender, &responder);
+            // Clients that were rejected on connect were never tracked
+            let Some(client) = clients.remove(&client_id) else {
+              continue;
+            };
+
+            handle_disconnect(client_id, &event_sender, &client);
+            None
           }
           Event::Message(client_id, message) => {
             log!(
@@ -95,118 +234,358 @@ impl WebsocketConnector {
               message
             );
 
-            let responder = clients.get_mut(&client_id).unwrap();
+            let Some(client) = clients.get_mut(&client_id) else {
+              log!(
+                "[Websocket] Ignoring message from untracked client {}",
+                client_id
+              );
+              continue;
+            };
             let message = match message {
               Message::Text(text) => text,
-              _ => "".to_string(),
+              // Clients that asked for ETF send binary messages, which are turned into JSON for the handlers
+              Message::Binary(data) => match etf::decode(&data) {
+                Ok(value) => value.to_string(),
+                Err(err) => {
+                  log!("[Websocket] Invalid ETF from client {}: {}", client_id, err);
+                  send_response(
+                    client,
+                    &CommandResponse::invalid_payload("", &format!("Invalid payload: {err}")),
+                  );
+                  continue;
+                }
+              },
             };
 
-            // If not ActivityCmd, ignore
+            // If not ActivityCmd, reject it
             let event: ActivityCmd = match serde_json::from_str(&message) {
               Ok(event) => event,
               Err(e) => {
                 log!("[Websocket] Invalid message from client {}", client_id);
                 log!("[Websocket] Error: {}", e);
+                send_response(
+                  client,
+                  &CommandResponse::invalid_payload(&message, &format!("Invalid payload: {e}")),
+                );
                 continue;
               }
             };
 
-            // If origin isn't a Discord URL, ignore
-            let origin = responder.1.connection_details().headers.get("origin");
-
-            if let Some(origin) = origin {
-              let value = origin.to_str().unwrap_or_default();
-              let valid = [
-                "https://discord.com",
-                "https://canary.discord.com",
-                "https://ptb.discord.com",
-              ];
-
-              if !valid.contains(&value) {
-                log!("[Websocket] Invalid origin from client {}", client_id);
-                continue;
-              }
-            }
-
             match event.cmd.as_str() {
-              "INVITE_BROWSER" => {
-                if !secondary_events {
-                  continue;
-                }
-
-                handle_invite(&event, &event_sender, &responder.1)
+              "INVITE_BROWSER" if secondary_events => {
+                handle_invite(event, &pending_invites, client)
               }
-              "SET_ACTIVITY" => {
-                if !set_activity {
-                  continue;
-                }
-
-                handle_set_activity(&event, &event_sender, responder)
+              "DEEP_LINK" if secondary_events => handle_deep_link(event, &message, client),
+              "GUILD_TEMPLATE_BROWSER" if secondary_events => {
+                handle_guild_template(event, &message, client)
+              }
+              "CONNECTIONS_CALLBACK" if secondary_events => {
+                handle_connections_callback(event, &message, client)
+              }
+              "SET_ACTIVITY" if set_activity => {
+                handle_set_activity(event, &event_sender, client);
+                None
               }
-              "DEEP_LINK" => {
-                log!("[Websocket] Deep link unimplemented. PRs are open!");
+              "SUBSCRIBE" | "UNSUBSCRIBE" => {
+                handle_subscription(event, client);
+                None
               }
               _ => {
-                log!("[Websocket] Unknown command: {}", event.cmd);
+                log!("[Websocket] Unsupported command: {}", event.cmd);
+
+                send_response(
+                  client,
+                  &CommandResponse::error(
+                    event.cmd,
+                    event.nonce,
+                    RpcErrorCode::InvalidCommand,
+                    "Invalid command",
+                  ),
+                );
+                None
               }
             }
           }
+        };
+
+        // Emitted once the clients are unlocked, so callbacks can dispatch to clients without deadlocking
+        drop(clients);
+
+        if let Some(event) = emitted {
+          events.emit(event);
         }
       }
+
+      // Clear any activities left behind, then close the connections before the server shuts down
+      for (client_id, client) in clients.lock().unwrap().drain() {
+        handle_disconnect(client_id, &event_sender, &client);
+        client.responder.close();
+      }
+
+      server.shutdown();
+
+      log!("[Websocket] Stopped");
     });
+
+    self.threads.lock().unwrap().push(handle);
+
+    Ok(())
+  }
+
+  /**
+   * Stop the websocket server thread and wait for it to exit
+   */
+  pub fn stop(&self) {
+    self.running.store(false, Ordering::Relaxed);
+    join_all(&self.threads, "Websocket");
+  }
+
+  /**
+   * Send a DISPATCH message to every client with the given socket id that subscribed to `evt`. Returns whether
+   * any client received it.
+   */
+  pub fn dispatch(&self, socket_id: &str, evt: &str, data: &Value) -> bool {
+    let response = CommandResponse::dispatch(evt, data.clone());
+    let mut sent = false;
+
+    for client in self.clients.lock().unwrap().values() {
+      let pid = client
+        .activity
+        .as_ref()
+        .and_then(|activity| activity.args.as_ref())
+        .and_then(|args| args.pid)
+        .unwrap_or_default();
+
+      if pid.to_string() == socket_id
+        && client.subscriptions.contains(evt)
+        && client.responder.send(client.encoding.message(&response))
+      {
+        sent = true;
+      }
+    }
+
+    sent
   }
 }
 
-fn event_args_as_hashmap(args: Option<ActivityCmdArgs>) -> HashMap<String, String> {
-  // Serde serialize the args
-  let args = match args {
-    Some(args) => serde_json::to_string(&args).unwrap_or("".to_string()),
-    None => "{}".to_string(),
+fn handle_invite(
+  event: ActivityCmd,
+  pending_invites: &PendingInvites,
+  client: &WebsocketClient,
+) -> Option<RPCEvent> {
+  let Some(code) = event.args.and_then(|args| args.code) else {
+    send_response(
+      client,
+      &CommandResponse::error(
+        event.cmd,
+        event.nonce,
+        RpcErrorCode::InvalidPayload,
+        "Missing invite code",
+      ),
+    );
+    return None;
   };
 
-  // Re-deserialize the args as a hashmap
-  serde_json::from_str::<HashMap<String, String>>(&args).unwrap_or_default()
+  // The response is sent once someone says what became of the invite
+  let request_id = pending_invites.add(
+    code.clone(),
+    event.nonce,
+    client.responder.clone(),
+    client.encoding,
+  );
+
+  Some(RPCEvent::InviteBrowser { request_id, code })
 }
 
-fn handle_invite(
-  event: &ActivityCmd,
-  event_sender: &mpsc::Sender<ActivityCmd>,
-  responder: &Responder,
-) {
-  // Let's just assume this went well I don't care
-  let response = ActivityCmd {
-    application_id: event.application_id.clone(),
-    cmd: event.cmd.clone(),
-    args: None,
-    data: Some(event_args_as_hashmap(event.args.clone())),
-    evt: None,
-    nonce: event.nonce.clone(),
+fn handle_deep_link(
+  event: ActivityCmd,
+  message: &str,
+  client: &WebsocketClient,
+) -> Option<RPCEvent> {
+  // The link's type and params don't fit in ActivityCmdArgs, so get them from the raw message
+  let value = serde_json::from_str::<Value>(message).unwrap_or_default();
+
+  let (response, emitted) = match DeepLink::from_args(&value["args"]) {
+    Some(link) => {
+      log!("[Websocket] Deep link: {:?}", link);
+
+      (
+        CommandResponse::new(event.cmd, Value::Null, event.nonce),
+        Some(RPCEvent::DeepLink(link)),
+      )
+    }
+    None => (
+      CommandResponse::error(
+        event.cmd,
+        event.nonce,
+        RpcErrorCode::InvalidPayload,
+        "Missing deep link type",
+      ),
+      None,
+    ),
+  };
+
+  send_response(client, &response);
+  emitted
+}
+
+fn handle_guild_template(
+  event: ActivityCmd,
+  message: &str,
+  client: &WebsocketClient,
+) -> Option<RPCEvent> {
+  let value = serde_json::from_str::<Value>(message).unwrap_or_default();
+  let args = &value["args"];
+
+  let (response, emitted) = match args["code"].as_str() {
+    Some(code) => (
+      // Echo the args back, like INVITE_BROWSER
+      CommandResponse::new(event.cmd, args.clone(), event.nonce),
+      Some(RPCEvent::GuildTemplateBrowser {
+        code: code.to_string(),
+      }),
+    ),
+    None => (
+      CommandResponse::error(
+        event.cmd,
+        event.nonce,
+        RpcErrorCode::InvalidPayload,
+        "Missing template code",
+      ),
+      None,
+    ),
   };
 
-  // Send the event away!
-  event_sender.send(event.clone()).unwrap();
+  send_response(client, &response);
+  emitted
+}
+
+fn handle_connections_callback(
+  event: ActivityCmd,
+  message: &str,
+  client: &WebsocketClient,
+) -> Option<RPCEvent> {
+  let value = serde_json::from_str::<Value>(message).unwrap_or_default();
+  let args = &value["args"];
+
+  let (response, emitted) = match (args["providerType"].as_str(), args["code"].as_str()) {
+    (Some(provider_type), Some(code)) => {
+      log!("[Websocket] Connections callback for {}", provider_type);
+
+      (
+        CommandResponse::new(event.cmd, args.clone(), event.nonce),
+        Some(RPCEvent::ConnectionsCallback {
+          provider_type: provider_type.to_string(),
+          code: code.to_string(),
+          state: args["state"].as_str().map(str::to_string),
+          open_id_params: Some(args["openIdParams"].clone()).filter(|params| !params.is_null()),
+        }),
+      )
+    }
+    _ => (
+      CommandResponse::error(
+        event.cmd,
+        event.nonce,
+        RpcErrorCode::InvalidPayload,
+        "Missing providerType or code",
+      ),
+      None,
+    ),
+  };
 
-  // Respond
-  responder.send(Message::Text(serde_json::to_string(&response).unwrap()));
+  send_response(client, &response);
+  emitted
 }
 
 fn handle_set_activity(
-  event: &ActivityCmd,
+  event: ActivityCmd,
   event_sender: &mpsc::Sender<ActivityCmd>,
-  responder: &mut ActivityResponder,
+  client: &mut WebsocketClient,
 ) {
+  let Some(args) = event.args.as_ref() else {
+    log!("[Websocket] Invalid activity command, skipping");
+
+    send_response(
+      client,
+      &CommandResponse::error(
+        event.cmd,
+        event.nonce,
+        RpcErrorCode::InvalidPayload,
+        "Missing args",
+      ),
+    );
+    return;
+  };
+
+  // Respond with the activity as it was set, which is null when clearing it
+  let response = CommandResponse::new(
+    event.cmd.clone(),
+    serde_json::to_value(&args.activity).unwrap_or_default(),
+    event.nonce.clone(),
+  );
+
+  if let Err(err) = event_sender.send(event.clone()) {
+    log!("[Websocket] Error sending activity command: {}", err);
+
+    send_response(
+      client,
+      &CommandResponse::error(
+        response.cmd,
+        response.nonce,
+        RpcErrorCode::UnknownError,
+        "Could not set activity",
+      ),
+    );
+    return;
+  }
+
   // Set the last activity for the client
-  responder.0 = Some(event.clone());
+  client.activity = Some(event);
+
+  send_response(client, &response);
+}
+
+fn handle_subscription(event: ActivityCmd, client: &mut WebsocketClient) {
+  let evt = event.evt.unwrap_or_default();
+
+  let response = if SUBSCRIBABLE_EVENTS.contains(&evt.as_str()) {
+    if event.cmd == "SUBSCRIBE" {
+      client.subscriptions.insert(evt.clone());
+    } else {
+      client.subscriptions.remove(&evt);
+    }
+
+    CommandResponse::new(event.cmd, serde_json::json!({ "evt": evt }), event.nonce)
+  } else {
+    log!("[Websocket] Cannot {} to event: {}", event.cmd, evt);
 
-  event_sender.send(event.clone()).unwrap();
+    CommandResponse::error(
+      event.cmd,
+      event.nonce,
+      RpcErrorCode::InvalidEvent,
+      &format!("Invalid event: {evt}"),
+    )
+  };
+
+  send_response(client, &response);
+}
+
+fn send_response(client: &WebsocketClient, response: &CommandResponse) {
+  client.responder.send(client.encoding.message(response));
 }
 
 fn handle_disconnect(
   _client_id: u64,
   event_sender: &mpsc::Sender<ActivityCmd>,
-  responder: &ActivityResponder,
+  client: &WebsocketClient,
 ) {
-  if let Some(ref activity_cmd) = responder.0 {
+  if let Some(ref activity_cmd) = client.activity {
+    let pid = activity_cmd
+      .args
+      .as_ref()
+      .and_then(|args| args.pid)
+      .unwrap_or_default();
+
     // Send empty activity
     let activity_cmd = ActivityCmd {
       application_id: activity_cmd.application_id.clone(),
@@ -214,13 +593,15 @@ fn handle_disconnect(
       data: None,
       evt: None,
       args: Some(ActivityCmdArgs {
-        pid: Some(activity_cmd.args.as_ref().unwrap().pid.unwrap_or_default()),
+        pid: Some(pid),
         activity: None,
         code: None,
       }),
       nonce: activity_cmd.nonce.clone(),
     };
 
-    event_sender.send(activity_cmd).unwrap();
+    if let Err(err) = event_sender.send(activity_cmd) {
+      log!("[Websocket] Error sending empty activity: {}", err);
+    }
   }
 }
@@ -0,0 +1,254 @@
+/// Programs that run the actual game somewhere in their arguments
+const LAUNCHERS: &[&str] = &[
+  "wine",
+  "wine64",
+  "wine-preloader",
+  "wine64-preloader",
+  "wineloader",
+  "start.exe",
+  "proton",
+  "pressure-vessel-wrap",
+  "pressure-vessel-adverb",
+  "pv-adverb",
+  "srt-bwrap",
+  "steam-launch-wrapper",
+];
+
+/// Steam's process reaper. The REAPER DAW's binary has the same name, so it only counts as a launcher when given
+/// the `SteamLaunch` argument Steam always starts it with.
+const STEAM_REAPER: &str = "reaper";
+
+/**
+ * Turn a process path and its arguments into what the scanner matches against: lowercase, forward slashes and
+ * a leading '/'. Wine/Proton launchers are unwrapped to the program they run, drive prefixes are stripped, and
+ * anything inside a Steam library is made relative to `steamapps/common`, so e.g.
+ * `wine64-preloader Z:\home\me\.steam\steamapps\common\Game\game.exe -dx11` becomes `/game/game.exe` with `-dx11`
+ * as its arguments.
+ */
+pub fn normalize_process(path: &str, arguments: Option<&str>) -> (String, Option<String>) {
+  let mut path = path.to_string();
+  let mut arguments = arguments.map(str::to_string);
+
+  // Launchers can be nested (reaper -> pressure-vessel -> proton -> wine), so keep going until we hit something else
+  while is_launcher(&path, arguments.as_deref()) {
+    let Some((program, rest)) = arguments.as_deref().and_then(split_program) else {
+      break;
+    };
+
+    path = program;
+    arguments = rest;
+  }
+
+  let mut path = path.replace('\\', "/").to_ascii_lowercase();
+
+  // Z: is the Unix root and C: the prefix's drive_c, either way the rest of the path is what matters
+  if path.as_bytes().get(1) == Some(&b':') && path.as_bytes()[0].is_ascii_alphabetic() {
+    path.replace_range(0..2, "");
+  }
+
+  if let Some(index) = path.find("/steamapps/common/") {
+    path.replace_range(0..index + "/steamapps/common".len(), "");
+  }
+
+  if !path.starts_with('/') {
+    path.insert(0, '/');
+  }
+
+  (path, arguments)
+}
+
+fn is_launcher(path: &str, arguments: Option<&str>) -> bool {
+  let path = path.replace('\\', "/").to_ascii_lowercase();
+  let name = path.rsplit('/').next().unwrap_or(&path);
+
+  if name == STEAM_REAPER {
+    return arguments
+      .is_some_and(|arguments| arguments.split_whitespace().next() == Some("SteamLaunch"));
+  }
+
+  LAUNCHERS.contains(&name)
+}
+
+/**
+ * Find the program a launcher runs within its (space-joined) arguments, returning it and whatever comes after it
+ */
+fn split_program(arguments: &str) -> Option<(String, Option<String>)> {
+  // Wrappers tend to separate their own options from the command with "--"
+  let arguments = match arguments.split_once(" -- ") {
+    Some((_, command)) => command,
+    None => arguments.strip_prefix("-- ").unwrap_or(arguments),
+  }
+  .trim();
+
+  if arguments.is_empty() {
+    return None;
+  }
+
+  // Windows paths may contain spaces, so look for the end of the executable instead of splitting on whitespace
+  let lowercase = arguments.to_ascii_lowercase();
+  let exe_end = lowercase
+    .match_indices(".exe")
+    .map(|(index, _)| index + 4)
+    .find(|&end| {
+      lowercase[end..]
+        .chars()
+        .next()
+        .is_none_or(char::is_whitespace)
+    });
+
+  let (start, end) = match exe_end {
+    Some(end) => (exe_start(&arguments[..end]), end),
+    None => (
+      0,
+      arguments
+        .find(char::is_whitespace)
+        .unwrap_or(arguments.len()),
+    ),
+  };
+
+  let rest = arguments[end..].trim();
+
+  Some((
+    arguments[start..end].to_string(),
+    if rest.is_empty() {
+      None
+    } else {
+      Some(rest.to_string())
+    },
+  ))
+}
+
+/**
+ * Where the executable ending `arguments` starts: at its drive letter if it has one, otherwise where its Unix path
+ * starts
+ */
+fn exe_start(arguments: &str) -> usize {
+  let bytes = arguments.as_bytes();
+
+  let drive = (0..bytes.len().saturating_sub(2)).rev().find(|&index| {
+    bytes[index].is_ascii_alphabetic()
+      && bytes[index + 1] == b':'
+      && (bytes[index + 2] == b'\\' || bytes[index + 2] == b'/')
+      && (index == 0 || bytes[index - 1].is_ascii_whitespace())
+  });
+
+  drive.unwrap_or_else(|| unix_path_start(arguments))
+}
+
+/**
+ * Where the Unix path ending `arguments` starts. These may contain spaces too, so when the last word carries on a
+ * path (`.../Left 4 Dead 2/left4dead2.exe`) the path starts at the last word before it that begins with a '/'
+ */
+fn unix_path_start(arguments: &str) -> usize {
+  let last_word = arguments
+    .rfind(char::is_whitespace)
+    .map_or(0, |index| index + 1);
+  let word = &arguments[last_word..];
+
+  if word.starts_with('/') || !word.contains('/') {
+    return last_word;
+  }
+
+  let bytes = arguments.as_bytes();
+
+  (1..last_word)
+    .rev()
+    .find(|&index| bytes[index] == b'/' && bytes[index - 1].is_ascii_whitespace())
+    .or_else(|| (bytes.first() == Some(&b'/')).then_some(0))
+    .unwrap_or(last_word)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn normalizes_process_paths() {
+    let cases: &[(&str, Option<&str>, &str, Option<&str>)] = &[
+      // Native processes are only made consistent
+      ("/usr/bin/Game", Some("--fullscreen"), "/usr/bin/game", Some("--fullscreen")),
+      ("wine", None, "/wine", None),
+      // Drive letters
+      (
+        "wine64-preloader",
+        Some(r"Z:\home\me\Games\Game.exe -dx11"),
+        "/home/me/games/game.exe",
+        Some("-dx11"),
+      ),
+      (
+        "wine",
+        Some(r"C:\Program Files\Some Game\game.exe"),
+        "/program files/some game/game.exe",
+        None,
+      ),
+      (
+        r"C:\windows\system32\wineloader",
+        Some(r"C:\Games\game.exe"),
+        "/games/game.exe",
+        None,
+      ),
+      // Steam libraries
+      (
+        "wine64",
+        Some(r"Z:\home\me\.steam\steamapps\common\Game\game.exe -dx11"),
+        "/game/game.exe",
+        Some("-dx11"),
+      ),
+      (
+        "/home/me/.steam/steam/steamapps/common/Game/bin/game",
+        None,
+        "/game/bin/game",
+        None,
+      ),
+      // Wrappers and their "--" separators
+      (
+        "pressure-vessel-wrap",
+        Some("--env=A=1 -- /path/proton waitforexitandrun /home/me/steamapps/common/Left 4 Dead 2/left4dead2.exe"),
+        "/left 4 dead 2/left4dead2.exe",
+        None,
+      ),
+      (
+        "/home/me/.steam/steam/ubuntu12_32/reaper",
+        Some(
+          "SteamLaunch AppId=550 -- /home/me/.steam/steam/ubuntu12_32/steam-launch-wrapper -- \
+           /home/me/.steam/steam/steamapps/common/Proton 8.0/proton waitforexitandrun \
+           /home/me/.steam/steam/steamapps/common/Left 4 Dead 2/left4dead2.exe -game left4dead2",
+        ),
+        "/left 4 dead 2/left4dead2.exe",
+        Some("-game left4dead2"),
+      ),
+      (
+        "reaper",
+        Some("SteamLaunch AppId=1 -- /games/game.x86_64 -v"),
+        "/games/game.x86_64",
+        Some("-v"),
+      ),
+      // REAPER the DAW isn't Steam's reaper
+      (
+        "/usr/bin/reaper",
+        Some("/home/me/Music/Song.rpp"),
+        "/usr/bin/reaper",
+        Some("/home/me/Music/Song.rpp"),
+      ),
+      ("/opt/REAPER/reaper", None, "/opt/reaper/reaper", None),
+      // Nested launchers, with a Unix path with spaces at the end
+      (
+        "wine",
+        Some(r"C:\windows\command\start.exe /unix /home/me/Games/Some Game/game.exe"),
+        "/home/me/games/some game/game.exe",
+        None,
+      ),
+    ];
+
+    for (path, arguments, expected_path, expected_arguments) in cases {
+      assert_eq!(
+        normalize_process(path, *arguments),
+        (
+          expected_path.to_string(),
+          expected_arguments.map(str::to_string)
+        ),
+        "{path} {arguments:?}"
+      );
+    }
+  }
+}
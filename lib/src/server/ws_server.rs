@@ -0,0 +1,171 @@
+use std::{
+  io::{self, ErrorKind},
+  net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+  },
+  thread::{self, JoinHandle},
+  time::{Duration, Instant},
+};
+
+use simple_websockets::{Event, EventHub};
+
+use crate::{log, Error};
+
+/// How long open connections get to finish their closing handshake once the server shuts down
+const CLOSE_GRACE: Duration = Duration::from_millis(500);
+
+/**
+ * A websocket server that gives its port back when it is shut down. simple-websockets never stops accepting once
+ * launched, so it only ever listens on an ephemeral loopback port, and connections to the real port are forwarded
+ * to it by an accept loop we can stop.
+ */
+pub struct WebsocketServer {
+  hub: EventHub,
+  running: Arc<AtomicBool>,
+  accept_thread: Option<JoinHandle<()>>,
+  forwards: Arc<Mutex<Vec<Forward>>>,
+}
+
+impl WebsocketServer {
+  /**
+   * Launch a websocket server on a specific address, rather than the 0.0.0.0 `simple_websockets::launch` always uses
+   */
+  pub fn launch(address: IpAddr, port: u16) -> Result<Self, Error> {
+    let listener = TcpListener::bind((address, port)).map_err(|_| Error::PortInUse(port))?;
+    listener.set_nonblocking(true)?;
+
+    let inner = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let inner_address = inner.local_addr()?;
+    let hub = simple_websockets::launch_from_listener(inner).map_err(|_| Error::PortInUse(port))?;
+
+    let running = Arc::new(AtomicBool::new(true));
+    let forwards = Arc::new(Mutex::new(vec![]));
+
+    let accept_thread = {
+      let running = running.clone();
+      let forwards = forwards.clone();
+
+      thread::spawn(move || {
+        while running.load(Ordering::Relaxed) {
+          match listener.accept() {
+            Ok((client, _)) => match Forward::open(client, inner_address) {
+              Ok(forward) => {
+                // Forget about connections that already ended so the list doesn't grow forever
+                let mut forwards = forwards.lock().unwrap();
+                forwards.retain(|forward: &Forward| !forward.handle.is_finished());
+                forwards.push(forward);
+              }
+              Err(err) => log!("[Websocket] Failed to forward connection: {}", err),
+            },
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+              thread::sleep(Duration::from_millis(50));
+            }
+            Err(err) => log!("[Websocket] Error: {}", err),
+          }
+        }
+
+        // The listener is dropped here, which frees the port
+      })
+    };
+
+    Ok(Self {
+      hub,
+      running,
+      accept_thread: Some(accept_thread),
+      forwards,
+    })
+  }
+
+  pub fn next_event(&self) -> Option<Event> {
+    self.hub.next_event()
+  }
+
+  /**
+   * Stop accepting connections and free the port. Open connections get a moment to pass on their close frames
+   * before they are cut off.
+   */
+  pub fn shutdown(&mut self) {
+    self.running.store(false, Ordering::Relaxed);
+
+    let Some(accept_thread) = self.accept_thread.take() else {
+      return;
+    };
+
+    if accept_thread.join().is_err() {
+      log!("[Websocket] The accept thread panicked before shutdown");
+    }
+
+    let forwards = std::mem::take(&mut *self.forwards.lock().unwrap());
+    let deadline = Instant::now() + CLOSE_GRACE;
+
+    while Instant::now() < deadline && forwards.iter().any(|forward| !forward.handle.is_finished())
+    {
+      thread::sleep(Duration::from_millis(10));
+    }
+
+    for forward in forwards {
+      forward.close();
+    }
+  }
+}
+
+impl Drop for WebsocketServer {
+  fn drop(&mut self) {
+    // Servers that were never started are only ever shut down here
+    self.shutdown();
+  }
+}
+
+/**
+ * A connection to the real port, passed on to the websocket server in both directions
+ */
+struct Forward {
+  client: TcpStream,
+  upstream: TcpStream,
+  handle: JoinHandle<()>,
+}
+
+impl Forward {
+  fn open(client: TcpStream, to: SocketAddr) -> io::Result<Self> {
+    // Accepted streams inherit the listener's nonblocking mode on some platforms
+    client.set_nonblocking(false)?;
+    let upstream = TcpStream::connect(to)?;
+
+    let (mut client_read, mut upstream_write) = (client.try_clone()?, upstream.try_clone()?);
+    let (mut upstream_read, mut client_write) = (upstream.try_clone()?, client.try_clone()?);
+
+    let handle = thread::spawn(move || {
+      let inbound = thread::spawn(move || pipe(&mut client_read, &mut upstream_write));
+      pipe(&mut upstream_read, &mut client_write);
+      let _ = inbound.join();
+    });
+
+    Ok(Self {
+      client,
+      upstream,
+      handle,
+    })
+  }
+
+  /**
+   * Cut off both ends, then wait for the forwarding threads to notice
+   */
+  fn close(self) {
+    let _ = self.client.shutdown(Shutdown::Both);
+    let _ = self.upstream.shutdown(Shutdown::Both);
+
+    if self.handle.join().is_err() {
+      log!("[Websocket] A forwarding thread panicked before shutdown");
+    }
+  }
+}
+
+/**
+ * Copy everything from one end to the other, then tell the other end nothing more is coming
+ */
+fn pipe(from: &mut TcpStream, to: &mut TcpStream) {
+  let _ = io::copy(from, to);
+  let _ = to.shutdown(Shutdown::Write);
+}
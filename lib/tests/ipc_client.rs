@@ -0,0 +1,128 @@
+use rsrpc::{client::IpcClient, cmd::Activity, events::RPCEvent, Error, RPCConfig, RPCServer};
+use std::{path::PathBuf, sync::mpsc, thread, time::Duration};
+
+/**
+ * An IPC-only server with its sockets in a directory of its own, so tests don't get in each other's way
+ */
+fn ipc_server(name: &str) -> (RPCServer, PathBuf) {
+  let dir = std::env::temp_dir().join(format!("rsrpc-test-{}-{name}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+
+  let server = RPCServer::from_json_str(
+    "[]",
+    RPCConfig {
+      enable_process_scanner: false,
+      enable_websocket_connector: false,
+      enable_secondary_events: false,
+      enable_bridge: false,
+      ipc_socket_dir: Some(dir.clone()),
+      ..Default::default()
+    },
+  )
+  .unwrap();
+
+  (server, dir)
+}
+
+#[test]
+fn sets_and_clears_activity_over_ipc() {
+  let (mut server, dir) = ipc_server("activity");
+  let events = server.subscribe();
+  server.start().unwrap();
+
+  // Handshakes are validated before anything else
+  assert!(matches!(
+    IpcClient::connect_with(Some(&dir), "discord-ipc", "not-a-snowflake"),
+    Err(Error::IpcClosed(4000, _))
+  ));
+
+  let mut client = IpcClient::connect_with(Some(&dir), "discord-ipc", "1234567890").unwrap();
+  assert_eq!(client.ready()["evt"], "READY");
+
+  let response = client
+    .set_activity(Some(Activity {
+      details: Some("Testing".to_string()),
+      ..Default::default()
+    }))
+    .unwrap();
+  assert_eq!(response["cmd"], "SET_ACTIVITY");
+  assert_eq!(response["data"]["details"], "Testing");
+  assert_eq!(response["data"]["application_id"], "1234567890");
+
+  let response = client.subscribe("GUILD_STATUS").unwrap();
+  assert_eq!(response["evt"], "ERROR");
+  assert_eq!(response["data"]["code"], 4004);
+
+  // ClientConnected comes first
+  let connected = events.recv_timeout(Duration::from_secs(5));
+  assert!(matches!(connected, Ok(RPCEvent::ClientConnected { .. })));
+
+  let set = events.recv_timeout(Duration::from_secs(5));
+  assert!(matches!(set, Ok(RPCEvent::ActivitySet { .. })));
+
+  client.close().unwrap();
+
+  let cleared = events.recv_timeout(Duration::from_secs(5));
+  assert!(matches!(cleared, Ok(RPCEvent::ActivityCleared { .. })));
+
+  server.stop();
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn only_clears_activities_a_session_set() {
+  let (mut server, dir) = ipc_server("clear");
+  let events = server.subscribe();
+  server.start().unwrap();
+
+  let mut game = IpcClient::connect_with(Some(&dir), "discord-ipc", "1234567890").unwrap();
+  game
+    .set_activity(Some(Activity {
+      details: Some("Playing".to_string()),
+      ..Default::default()
+    }))
+    .unwrap();
+
+  // Connecting and leaving without setting an activity must not clear anyone else's
+  let idle = IpcClient::connect_with(Some(&dir), "discord-ipc", "1234567890").unwrap();
+  idle.close().unwrap();
+  game.close().unwrap();
+
+  let cleared: Vec<String> =
+    std::iter::from_fn(|| events.recv_timeout(Duration::from_millis(500)).ok())
+      .filter_map(|event| match event {
+        RPCEvent::ActivityCleared { socket_id, .. } => Some(socket_id),
+        _ => None,
+      })
+      .collect();
+  assert_eq!(cleared, vec![std::process::id().to_string()]);
+
+  server.stop();
+  let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn stops_with_a_client_connected() {
+  let (mut server, dir) = ipc_server("stop");
+  server.start().unwrap();
+
+  let mut client = IpcClient::connect_with(Some(&dir), "discord-ipc", "1234567890").unwrap();
+
+  // stop() waits on the client's handler, which must not be stuck reading from it
+  let (stopped_sender, stopped) = mpsc::channel();
+  let stopping = thread::spawn(move || {
+    server.stop();
+    let _ = stopped_sender.send(());
+  });
+
+  assert!(stopped.recv_timeout(Duration::from_secs(5)).is_ok());
+  stopping.join().unwrap();
+
+  // SDKs are told to reconnect rather than left with a dead socket
+  assert!(matches!(
+    client.recv(),
+    Err(Error::IpcClosed(1000, message)) if message == "Server shutting down"
+  ));
+
+  let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,41 @@
+use rsrpc::{RPCConfig, RPCServer};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+/**
+ * A port nothing is listening on right now
+ */
+fn free_port() -> u16 {
+  TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+    .unwrap()
+    .local_addr()
+    .unwrap()
+    .port()
+}
+
+#[test]
+fn restarts_with_the_websocket_and_bridge_enabled() {
+  let bridge_port = free_port();
+  let websocket_port = free_port();
+
+  let mut server = RPCServer::from_json_str(
+    "[]",
+    RPCConfig {
+      enable_process_scanner: false,
+      enable_ipc_connector: false,
+      bridge_port,
+      websocket_ports: websocket_port..websocket_port + 1,
+      ..Default::default()
+    },
+  )
+  .unwrap();
+
+  for _ in 0..2 {
+    server.start().unwrap();
+    assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, bridge_port)).is_ok());
+    assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, websocket_port)).is_ok());
+
+    server.stop();
+    assert!(TcpListener::bind((Ipv4Addr::LOCALHOST, bridge_port)).is_ok());
+    assert!(TcpListener::bind((Ipv4Addr::LOCALHOST, websocket_port)).is_ok());
+  }
+}